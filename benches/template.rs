@@ -0,0 +1,57 @@
+#[macro_use]
+extern crate criterion;
+extern crate stt;
+
+use criterion::Criterion;
+use std::collections::HashMap;
+use stt::{RenderBuffer, Template};
+
+fn small_spec() -> String {
+    "Hello $name$, you have $count$ new messages.".to_string()
+}
+
+fn large_spec() -> String {
+    (0..200)
+        .map(|i| format!("line {} has $var{}$ and $$ signs; ", i, i % 20))
+        .collect()
+}
+
+fn values() -> HashMap<String, String> {
+    let mut values: HashMap<String, String> = (0..20)
+        .map(|i| (format!("var{}", i), format!("value {}", i)))
+        .collect();
+    values.insert("name".to_string(), "world".to_string());
+    values.insert("count".to_string(), "3".to_string());
+    values
+}
+
+fn parse(c: &mut Criterion) {
+    let small = small_spec();
+    let large = large_spec();
+    c.bench_function("parse small", |b| b.iter(|| Template::new(&small).unwrap()));
+    c.bench_function("parse large", |b| b.iter(|| Template::new(&large).unwrap()));
+}
+
+fn render(c: &mut Criterion) {
+    let small = Template::new(&small_spec()).unwrap();
+    let large = Template::new(&large_spec()).unwrap();
+    let values = values();
+    c.bench_function("render small", |b| b.iter(|| small.render(&values)));
+    c.bench_function("render large", |b| b.iter(|| large.render(&values)));
+
+    let mut buffer = RenderBuffer::new();
+    c.bench_function("render large reusing", |b| {
+        b.iter(|| large.render_reusing(&mut buffer, &values).len())
+    });
+}
+
+fn partial(c: &mut Criterion) {
+    let small = Template::new(&small_spec()).unwrap();
+    let large = Template::new(&large_spec()).unwrap();
+    let values = values();
+    c.bench_function("partial small", |b| b.iter(|| small.partial(&values)));
+    c.bench_function("partial large", |b| b.iter(|| large.partial(&values)));
+}
+
+criterion_group!(benches, parse, render, partial);
+criterion_main!(benches);
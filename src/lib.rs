@@ -5,10 +5,134 @@
 //! let lookup = stt::SingleLookup::new("who","world");
 //! assert_eq!(template.render(&lookup),"Hello world!");
 //! ```
-#[derive(Debug, PartialEq, Clone)]
+#[cfg(feature = "base64")]
+extern crate base64;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "hex")]
+extern crate hex;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(test)]
+extern crate proptest;
+#[cfg(feature = "percent-encoding")]
+extern crate percent_encoding;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "derive")]
+extern crate stt_derive;
+
+/// Derives `Lookup` for a struct whose fields are all `AsRef<str>`, resolving each field name to
+/// the field's text.
+#[cfg(feature = "derive")]
+pub use stt_derive::Lookup;
+
+/// Derives `OwnedLookup` for a struct, resolving each field name to the `Display` output of the
+/// field, for use with `Template::render_owned`.
+#[cfg(feature = "derive")]
+pub use stt_derive::OwnedLookup;
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{ControlFlow, Deref, Range};
+use std::panic::{self, RefUnwindSafe};
+#[cfg(feature = "std")]
+use std::path::Path;
+use std::rc::{Rc, Weak};
+use std::string::FromUtf16Error;
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// The text of a constant fragment, either owned or shared between fragments, see
+/// `Template::partial_shared`.
+#[derive(Debug, Clone)]
+enum Text {
+    Owned(String),
+    Shared(Arc<str>),
+}
+
+impl Text {
+    /// Returns the text for modification, copying it first if it is shared.
+    fn to_mut(&mut self) -> &mut String {
+        if let Text::Shared(shared) = self {
+            *self = Text::Owned(shared.to_string());
+        }
+        match self {
+            Text::Owned(text) => text,
+            Text::Shared(_) => unreachable!(),
+        }
+    }
+
+    fn into_string(self) -> String {
+        match self {
+            Text::Owned(text) => text,
+            Text::Shared(shared) => shared.to_string(),
+        }
+    }
+}
+
+impl Default for Text {
+    fn default() -> Self {
+        Text::Owned(String::new())
+    }
+}
+
+impl Deref for Text {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Text::Owned(text) => text,
+            Text::Shared(shared) => shared,
+        }
+    }
+}
+
+impl From<String> for Text {
+    fn from(text: String) -> Self {
+        Text::Owned(text)
+    }
+}
+
+impl PartialEq for Text {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Text {}
+
+impl PartialOrd for Text {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Text {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 enum Fragment {
-    Constant(String),
-    Variable(String),
+    Constant(Text),
+    Variable(Arc<str>),
 }
 
 #[derive(PartialEq, Debug)]
@@ -17,13 +141,117 @@ enum Mode {
     Variable,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// A parsed template.
+///
+/// Templates are ordered by their internal structure, fragment by fragment, which roughly
+/// matches the order of their specs.
+#[derive(Debug, Clone)]
 pub struct Template {
     fragments: Vec<Fragment>,
+    has_variables: bool,
+    size_hint: usize,
+    spec: OnceLock<String>,
+    // The parsed defaults of variables written as `$name:default$`, by variable, for templates
+    // parsed with `ParseOptions::nested_defaults`.
+    defaults: HashMap<Arc<str>, Template>,
+}
+
+impl PartialEq for Template {
+    fn eq(&self, other: &Template) -> bool {
+        self.fragments == other.fragments
+    }
+}
+
+impl Eq for Template {}
+
+impl PartialOrd for Template {
+    fn partial_cmp(&self, other: &Template) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Template {
+    fn cmp(&self, other: &Template) -> Ordering {
+        self.fragments.cmp(&other.fragments)
+    }
 }
 
 pub trait Lookup {
     fn lookup(&self, key: &str) -> Option<&str>;
+
+    /// Interprets the value of `key` as a boolean: `"true"` and `"1"` are true, while `"false"`,
+    /// `"0"` and the empty string are false. Missing keys and other values give `None`.
+    fn lookup_bool(&self, key: &str) -> Option<bool> {
+        match self.lookup(key)? {
+            "true" | "1" => Some(true),
+            "false" | "0" | "" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl<L: Lookup + ?Sized> Lookup for &L {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        (**self).lookup(key)
+    }
+}
+
+/// An optional lookup layer, resolving nothing when absent.
+impl<L: Lookup> Lookup for Option<L> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.as_ref().and_then(|lookup| lookup.lookup(key))
+    }
+}
+
+/// A lookup producing owned values, for sources that have no `&str` to lend out, such as
+/// values computed on demand. Every `Lookup` is also an `OwnedLookup`.
+pub trait OwnedLookup {
+    fn lookup_owned(&self, key: &str) -> Option<String>;
+}
+
+impl<L: Lookup + ?Sized> OwnedLookup for L {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        self.lookup(key).map(|value| value.to_string())
+    }
+}
+
+/// A lookup producing values that are either borrowed or owned, so that computed values can be
+/// mixed with borrowed ones without copying the latter. Every `Lookup` is also a `CowLookup`.
+pub trait CowLookup {
+    fn lookup_cow(&self, key: &str) -> Option<Cow<'_, str>>;
+}
+
+impl<L: Lookup + ?Sized> CowLookup for L {
+    fn lookup_cow(&self, key: &str) -> Option<Cow<'_, str>> {
+        self.lookup(key).map(Cow::Borrowed)
+    }
+}
+
+/// Resolves keys by calling a closure that returns either borrowed or owned values.
+pub struct CowFnLookup<'v, F> {
+    f: F,
+    values: PhantomData<Cow<'v, str>>,
+}
+
+impl<'v, F: Fn(&str) -> Option<Cow<'v, str>>> CowFnLookup<'v, F> {
+    pub fn new(f: F) -> Self {
+        CowFnLookup {
+            f,
+            values: PhantomData,
+        }
+    }
+}
+
+impl<'v, F: Fn(&str) -> Option<Cow<'v, str>>> CowLookup for CowFnLookup<'v, F> {
+    fn lookup_cow(&self, key: &str) -> Option<Cow<'_, str>> {
+        (self.f)(key)
+    }
+}
+
+/// A lookup that may change state while resolving, such as a counter or a cache, see
+/// `Template::render_mut`.
+pub trait MutLookup {
+    fn lookup_mut(&mut self, key: &str) -> Option<String>;
 }
 
 pub struct ConstantLookup {
@@ -43,12 +271,19 @@ impl Lookup for ConstantLookup {
 }
 
 pub struct EmptyLookup {}
+
 impl EmptyLookup {
     pub fn new() -> Self {
         EmptyLookup {}
     }
 }
 
+impl Default for EmptyLookup {
+    fn default() -> Self {
+        EmptyLookup::new()
+    }
+}
+
 impl Lookup for EmptyLookup {
     fn lookup(&self, _key: &str) -> Option<&str> {
         None
@@ -79,21 +314,144 @@ impl Lookup for SingleLookup {
     }
 }
 
+/// Resolves keys from a map of borrowed string slices, without copying them.
+impl<'a> Lookup for HashMap<&'a str, &'a str> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.get(key).copied()
+    }
+}
+
+impl Lookup for HashMap<String, String> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.get(key).map(|value| value.as_str())
+    }
+}
+
+/// Builds a lookup from `name => value` pairs, where each value may be anything implementing
+/// `Display`.
+///
+/// ```
+/// # #[macro_use] extern crate stt;
+/// # fn main() {
+/// let template = stt::Template::new("$who$ is $age$").unwrap();
+/// assert_eq!(template.render(&vars! { who => "Ann", age => 42 }), "Ann is 42");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! vars {
+    ($($name:ident => $value:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut map = ::std::collections::HashMap::<String, String>::new();
+        $(map.insert(stringify!($name).to_string(), $value.to_string());)*
+        map
+    }};
+}
+
+/// Resolves keys from a slice of `(key, value)` pairs by linear search, the first matching pair
+/// winning.
+impl<'a> Lookup for [(&'a str, &'a str)] {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.iter()
+            .find(|(candidate, _)| *candidate == key)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Conversion into a lookup, letting `Template::render_into` accept maps, slices of pairs and
+/// closures directly.
+pub trait IntoLookup {
+    type Lookup: OwnedLookup;
+
+    fn into_lookup(self) -> Self::Lookup;
+}
+
+impl IntoLookup for HashMap<String, String> {
+    type Lookup = Self;
+
+    fn into_lookup(self) -> Self {
+        self
+    }
+}
+
+impl<'a> IntoLookup for HashMap<&'a str, &'a str> {
+    type Lookup = Self;
+
+    fn into_lookup(self) -> Self {
+        self
+    }
+}
+
+impl<'a> IntoLookup for &'a [(&'a str, &'a str)] {
+    type Lookup = Self;
+
+    fn into_lookup(self) -> Self {
+        self
+    }
+}
+
+impl<'a, const N: usize> IntoLookup for &'a [(&'a str, &'a str); N] {
+    type Lookup = &'a [(&'a str, &'a str)];
+
+    fn into_lookup(self) -> Self::Lookup {
+        self
+    }
+}
+
+impl IntoLookup for &dyn Lookup {
+    type Lookup = Self;
+
+    fn into_lookup(self) -> Self {
+        self
+    }
+}
+
+impl<F: Fn(&str) -> Option<String>> IntoLookup for F {
+    type Lookup = FnLookup<F>;
+
+    fn into_lookup(self) -> FnLookup<F> {
+        FnLookup(self)
+    }
+}
+
+/// Resolves keys by calling a closure.
+pub struct FnLookup<F>(pub F);
+
+impl<F: Fn(&str) -> Option<String>> OwnedLookup for FnLookup<F> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        (self.0)(key)
+    }
+}
+
 pub struct ChainedLookup<'a> {
-    lookups: Vec<&'a Lookup>,
+    lookups: Vec<&'a dyn Lookup>,
 }
 
 impl<'a> ChainedLookup<'a> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         ChainedLookup {
             lookups: Vec::new(),
         }
     }
 
-    fn add(&mut self, lookup: &'a Lookup) -> &Self {
+    pub fn add(&mut self, lookup: &'a dyn Lookup) -> &Self {
         self.lookups.push(lookup);
         self
     }
+
+    /// Resolves `key` like `lookup`, also returning the index of the lookup that supplied the
+    /// value, counting from 0 in the order they were added.
+    pub fn lookup_with_source(&self, key: &str) -> Option<(usize, &str)> {
+        self.lookups
+            .iter()
+            .enumerate()
+            .find_map(|(index, lookup)| Some((index, lookup.lookup(key)?)))
+    }
+}
+
+impl<'a> Default for ChainedLookup<'a> {
+    fn default() -> Self {
+        ChainedLookup::new()
+    }
 }
 
 impl<'a> Lookup for ChainedLookup<'a> {
@@ -108,136 +466,6224 @@ impl<'a> Lookup for ChainedLookup<'a> {
     }
 }
 
-#[derive(PartialEq, Debug)]
-pub enum ParseError {
-    UNTERMINATED_VARIABLE,
+/// Resolves keys from command-line style arguments.
+///
+/// Both `--key value` and `--key=value` are accepted. A flag without a value resolves to an
+/// empty string, arguments not starting with `--` are ignored and the last occurrence of a
+/// key wins. A bare `--` ends the options; the arguments after it are ignored.
+pub struct ArgvLookup {
+    values: HashMap<String, String>,
 }
 
-impl Template {
-    pub fn new(spec: &str) -> Result<Template, ParseError> {
-        let mut result = Vec::new();
-        let mut buf = String::new();
-        let mut mode = Mode::Constant;
-        for c in spec.chars() {
-            match c {
-                '$' => match mode {
-                    Mode::Constant if buf.len() > 0 => {
-                        result.push(Fragment::Constant(buf.drain(..).collect()));
-                        mode = Mode::Variable;
-                    }
-                    Mode::Variable if buf.len() == 0 => {
-                        buf.push(c);
-                        mode = Mode::Constant;
-                    }
-                    Mode::Variable => {
-                        result.push(Fragment::Variable(buf.drain(..).collect()));
-                        mode = Mode::Constant;
-                    }
-                    _ => mode = Mode::Variable,
-                },
-                _ => buf.push(c),
+impl ArgvLookup {
+    pub fn new(args: Vec<String>) -> Self {
+        let mut values = HashMap::new();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+            if !arg.starts_with("--") {
+                continue;
+            }
+            let flag = &arg[2..];
+            match flag.find('=') {
+                Some(index) => {
+                    values.insert(flag[..index].to_string(), flag[index + 1..].to_string());
+                }
+                None => {
+                    let value = match args.peek() {
+                        Some(next) if !next.starts_with("--") => args.next(),
+                        _ => None,
+                    };
+                    values.insert(flag.to_string(), value.unwrap_or_default());
+                }
             }
         }
+        ArgvLookup { values }
+    }
+}
 
-        if mode == Mode::Variable {
-            Err(ParseError::UNTERMINATED_VARIABLE)
-        } else {
-            if buf.len() > 0 {
-                result.push(Fragment::Constant(buf.drain(..).collect()));
-            }
+impl Lookup for ArgvLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+}
+
+struct OwnedChainedLookup {
+    lookups: Vec<Box<dyn Lookup>>,
+}
 
-            Ok(Template { fragments: result })
+impl Lookup for OwnedChainedLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.lookups.iter().find_map(|lookup| lookup.lookup(key))
+    }
+}
+
+/// Resolves top-level keys of a JSON object. Strings resolve to their contents and numbers and
+/// booleans to their JSON representation, while `null`, arrays and objects do not resolve.
+#[cfg(feature = "json")]
+impl OwnedLookup for serde_json::Map<String, serde_json::Value> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        match self.get(key)? {
+            serde_json::Value::String(text) => Some(text.clone()),
+            serde_json::Value::Number(number) => Some(number.to_string()),
+            serde_json::Value::Bool(value) => Some(value.to_string()),
+            _ => None,
         }
     }
+}
 
-    pub fn set(self, key: &str, value: &str) -> Template {
-        self.partial(&SingleLookup::new(key, value))
+/// Resolves dotted key paths such as `user.address.city` into a JSON value tree, where a numeric
+/// segment indexes into an array. Strings, numbers and booleans resolve; other values do not.
+#[cfg(feature = "json")]
+pub struct JsonLookup {
+    value: serde_json::Value,
+}
+
+#[cfg(feature = "json")]
+impl JsonLookup {
+    pub fn new(value: serde_json::Value) -> Self {
+        JsonLookup { value }
     }
 
-    pub fn partial(&self, lookup: &Lookup) -> Template {
-        let mut fragments = Vec::new();
-        for fragment in &self.fragments {
-            match fragment {
-                Fragment::Variable(ref var) => match lookup.lookup(var) {
-                    Some(value) => fragments.push(Fragment::Constant(value.to_owned())),
-                    _ => fragments.push(fragment.clone()),
-                },
-                _ => fragments.push(fragment.clone()),
-            }
-        }
-        Template { fragments }
+    /// Builds the lookup from any serializable data, serializing it to a value tree once.
+    pub fn from_serialize<T: serde::Serialize>(data: &T) -> Result<Self, serde_json::Error> {
+        Ok(JsonLookup::new(serde_json::to_value(data)?))
     }
+}
 
-    pub fn render(&self, lookup: &Lookup) -> String {
-        let mut result = String::new();
-        for fragment in &self.fragments {
-            match fragment {
-                Fragment::Constant(text) => result.push_str(text),
-                Fragment::Variable(var) => match lookup.lookup(var) {
-                    Some(text) => result.push_str(text),
-                    _ => (),
-                },
-            }
+#[cfg(feature = "json")]
+impl OwnedLookup for JsonLookup {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        let mut value = &self.value;
+        for segment in key.split('.') {
+            value = match value {
+                serde_json::Value::Object(map) => map.get(segment)?,
+                serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        match value {
+            serde_json::Value::String(text) => Some(text.clone()),
+            serde_json::Value::Number(number) => Some(number.to_string()),
+            serde_json::Value::Bool(value) => Some(value.to_string()),
+            _ => None,
         }
-        result
     }
+}
 
-    pub fn as_spec(&self) -> String {
-        let mut spec = String::new();
-        for fragment in &self.fragments {
-            match fragment {
-                Fragment::Constant(text) => if text == "$" {
-                    spec.push_str("$$");
-                } else {
-                    spec.push_str(text);
-                },
-                Fragment::Variable(var) => {
-                    spec.push('$');
-                    spec.push_str(var);
-                    spec.push('$');
+/// Resolves keys from a map whose keys are already flattened into dotted paths such as
+/// `user.name`.
+pub struct FlatLookup {
+    values: HashMap<String, String>,
+}
+
+impl FlatLookup {
+    pub fn new(values: HashMap<String, String>) -> Self {
+        FlatLookup { values }
+    }
+
+    /// Flattens a nested JSON value into dotted keys, with array elements keyed by index.
+    /// Strings, numbers and booleans become values; nulls are left out.
+    #[cfg(feature = "json")]
+    pub fn flatten(value: &serde_json::Value) -> FlatLookup {
+        fn walk(prefix: &str, value: &serde_json::Value, values: &mut HashMap<String, String>) {
+            let key = |segment: &str| match prefix {
+                "" => segment.to_string(),
+                _ => format!("{}.{}", prefix, segment),
+            };
+            match value {
+                serde_json::Value::Object(map) => {
+                    for (segment, value) in map {
+                        walk(&key(segment), value, values);
+                    }
+                }
+                serde_json::Value::Array(items) => {
+                    for (index, value) in items.iter().enumerate() {
+                        walk(&key(&index.to_string()), value, values);
+                    }
+                }
+                serde_json::Value::String(text) => {
+                    values.insert(prefix.to_string(), text.clone());
+                }
+                serde_json::Value::Number(number) => {
+                    values.insert(prefix.to_string(), number.to_string());
+                }
+                serde_json::Value::Bool(flag) => {
+                    values.insert(prefix.to_string(), flag.to_string());
                 }
+                serde_json::Value::Null => (),
             }
         }
-        spec
+
+        let mut values = HashMap::new();
+        walk("", value, &mut values);
+        FlatLookup::new(values)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn empty_template_yields_empty_string() {
-        let template = Template::new("").unwrap();
-        assert_eq!(template.render(&EmptyLookup::new()), "");
+impl Lookup for FlatLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
     }
+}
 
-    #[test]
-    fn variable_only_yields_value_of_variable() {
-        let template = Template::new("$foo$").unwrap();
-        assert_eq!(template.render(&SingleLookup::new("foo", "value")), "value");
+/// Chains the given lookups, taking ownership of them. Like `ChainedLookup`, the first lookup
+/// that resolves a key wins.
+pub fn chain(lookups: Vec<Box<dyn Lookup>>) -> impl Lookup {
+    OwnedChainedLookup { lookups }
+}
+
+/// Resolves keys from a list of maps, where earlier layers take precedence over later ones.
+pub struct LayeredLookup {
+    layers: Vec<HashMap<String, String>>,
+}
+
+impl LayeredLookup {
+    pub fn new() -> Self {
+        LayeredLookup { layers: Vec::new() }
     }
 
-    #[test]
-    fn variable_wrapped_with_constants() {
-        let template = Template::new("Hello $who$!").unwrap();
-        assert_eq!(
-            template.render(&SingleLookup::new("who", "world")),
-            "Hello world!"
-        );
+    /// Adds a layer with lower precedence than all previously added layers.
+    pub fn add(&mut self, layer: HashMap<String, String>) -> &mut Self {
+        self.layers.push(layer);
+        self
     }
 
-    #[test]
-    fn chained_lookup() {
-        let first = SingleLookup::new("alfa", "beta");
+    /// Returns the keys, sorted, that are present in more than one layer.
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut conflicts = HashSet::new();
+        for layer in &self.layers {
+            for key in layer.keys() {
+                if !seen.insert(key) {
+                    conflicts.insert(key.clone());
+                }
+            }
+        }
+        let mut conflicts: Vec<String> = conflicts.into_iter().collect();
+        conflicts.sort();
+        conflicts
+    }
+}
+
+impl Default for LayeredLookup {
+    fn default() -> Self {
+        LayeredLookup::new()
+    }
+}
+
+impl Lookup for LayeredLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.layers
+            .iter()
+            .find_map(|layer| layer.get(key))
+            .map(|value| value.as_str())
+    }
+}
+
+/// Parses `spec` and returns the distinct variable names in order of first appearance.
+pub fn extract_variables(spec: &str) -> Result<Vec<String>, ParseError> {
+    let template = Template::new(spec)?;
+    let mut names: Vec<String> = Vec::new();
+    for fragment in template.fragments {
+        if let Fragment::Variable(var) = fragment {
+            if !names.iter().any(|name| **name == *var) {
+                names.push(var.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Parses `spec` and renders it against the environment variables of the process, rendering
+/// variables that are not set as the empty string.
+pub fn render_env(spec: &str) -> Result<String, TemplateError> {
+    let template = Template::new(spec)?;
+    Ok(template.render(&EnvLookup::new()))
+}
+
+/// Returns whether `lookup` resolves every variable of `template`, that is whether
+/// `Template::try_render` would succeed.
+pub fn covers(template: &Template, lookup: &dyn Lookup) -> bool {
+    template.fragments.iter().all(|fragment| match fragment {
+        Fragment::Variable(var) => lookup.lookup(var).is_some(),
+        _ => true,
+    })
+}
+
+/// Resolves keys from dotenv style `KEY=VALUE` text.
+pub struct DotenvLookup {
+    values: HashMap<String, String>,
+}
+
+impl DotenvLookup {
+    /// Parses dotenv text. Blank lines and lines starting with `#` are ignored, and values may be
+    /// wrapped in single or double quotes to keep surrounding whitespace.
+    pub fn parse(text: &str) -> Result<DotenvLookup, ParseError> {
+        let mut values = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let separator = match line.find('=') {
+                Some(separator) => separator,
+                None => return Err(ParseError::InvalidLine { line: index + 1 }),
+            };
+            let key = line[..separator].trim();
+            if key.is_empty() {
+                return Err(ParseError::InvalidLine { line: index + 1 });
+            }
+            let value = line[separator + 1..].trim();
+            let unquoted = if value.len() >= 2
+                && (value.starts_with('"') && value.ends_with('"')
+                    || value.starts_with('\'') && value.ends_with('\''))
+            {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            values.insert(key.to_string(), unquoted.to_string());
+        }
+        Ok(DotenvLookup { values })
+    }
+}
+
+impl Lookup for DotenvLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+}
+
+/// Resolves keys of the form `section.key` from a map of sections. Keys without a `.` do not
+/// resolve.
+pub struct NestedMapLookup {
+    sections: HashMap<String, HashMap<String, String>>,
+}
+
+impl NestedMapLookup {
+    pub fn new(sections: HashMap<String, HashMap<String, String>>) -> Self {
+        NestedMapLookup { sections }
+    }
+}
+
+impl Lookup for NestedMapLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let dot = key.find('.')?;
+        self.sections
+            .get(&key[..dot])?
+            .get(&key[dot + 1..])
+            .map(|value| value.as_str())
+    }
+}
+
+/// Resolves a key by joining the values of every inner lookup that resolves it, in the order the
+/// lookups were added, rather than taking the first like `ChainedLookup`. Keys that no inner
+/// lookup resolves do not resolve. Use with `Template::render_owned`.
+pub struct ConcatLookup<'a> {
+    separator: &'a str,
+    lookups: Vec<&'a dyn Lookup>,
+}
+
+impl<'a> ConcatLookup<'a> {
+    pub fn new(separator: &'a str) -> Self {
+        ConcatLookup {
+            separator,
+            lookups: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, lookup: &'a dyn Lookup) -> &mut Self {
+        self.lookups.push(lookup);
+        self
+    }
+}
+
+impl<'a> OwnedLookup for ConcatLookup<'a> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        let values: Vec<&str> = self
+            .lookups
+            .iter()
+            .filter_map(|lookup| lookup.lookup(key))
+            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(self.separator))
+        }
+    }
+}
+
+/// Wraps a lookup and surrounds every resolved value with a prefix and a suffix, e.g. quotes.
+/// Keys the inner lookup does not resolve stay missing. Use with `Template::render_owned`.
+pub struct WrapLookup<'a> {
+    inner: &'a dyn Lookup,
+    prefix: &'a str,
+    suffix: &'a str,
+}
+
+impl<'a> WrapLookup<'a> {
+    pub fn new(inner: &'a dyn Lookup, prefix: &'a str, suffix: &'a str) -> Self {
+        WrapLookup {
+            inner,
+            prefix,
+            suffix,
+        }
+    }
+}
+
+impl<'a> OwnedLookup for WrapLookup<'a> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        let value = self.inner.lookup(key)?;
+        let len = self.prefix.len() + value.len() + self.suffix.len();
+        let mut wrapped = String::with_capacity(len);
+        wrapped.push_str(self.prefix);
+        wrapped.push_str(value);
+        wrapped.push_str(self.suffix);
+        Some(wrapped)
+    }
+}
+
+/// Wraps a lookup and renders a fallback template for every key the inner lookup does not
+/// resolve, against the inner lookup with `$key$` bound to the missing key's name.
+/// Use with `Template::render_owned`.
+pub struct FallbackTemplateLookup<'a> {
+    inner: &'a dyn Lookup,
+    fallback: &'a Template,
+}
+
+impl<'a> FallbackTemplateLookup<'a> {
+    pub fn new(inner: &'a dyn Lookup, fallback: &'a Template) -> Self {
+        FallbackTemplateLookup { inner, fallback }
+    }
+}
+
+impl<'a> OwnedLookup for FallbackTemplateLookup<'a> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.inner.lookup(key) {
+            return Some(value.to_string());
+        }
+        let name = SingleLookup::new("key", key);
+        let mut chain = ChainedLookup::new();
+        chain.add(&name);
+        chain.add(self.inner);
+        Some(self.fallback.render(&chain))
+    }
+}
+
+/// Resolves keys to fields of a borrowed value through accessor closures registered per field
+/// name, for rendering structs without deriving `Lookup`. Use with `Template::render_owned`.
+pub struct StructLookup<'a, T> {
+    data: &'a T,
+    fields: HashMap<String, Accessor<'a, T>>,
+}
+
+type Accessor<'a, T> = Box<dyn Fn(&T) -> String + 'a>;
+
+impl<'a, T> StructLookup<'a, T> {
+    pub fn new(data: &'a T) -> Self {
+        StructLookup {
+            data,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Registers the accessor resolving `name`, replacing any previous one.
+    pub fn field<F>(&mut self, name: &str, accessor: F) -> &mut Self
+    where
+        F: Fn(&T) -> String + 'a,
+    {
+        self.fields.insert(name.to_string(), Box::new(accessor));
+        self
+    }
+}
+
+impl<'a, T> OwnedLookup for StructLookup<'a, T> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        self.fields.get(key).map(|accessor| accessor(self.data))
+    }
+}
+
+/// Resolves variable names as message IDs from a translation catalog, falling back to the ID
+/// itself when it has no translation. A translation may hold a singular and a plural form
+/// separated by `|`; `$msg:count$` then picks the singular form when `count`, a number or a
+/// variable of the arguments lookup, is 1 and the plural form otherwise.
+/// Use with `Template::render_owned`.
+pub struct CatalogLookup<'a> {
+    catalog: HashMap<String, String>,
+    args: &'a dyn Lookup,
+}
+
+impl CatalogLookup<'static> {
+    pub fn new(catalog: HashMap<String, String>) -> Self {
+        CatalogLookup::with_args(catalog, &EmptyLookup {})
+    }
+}
+
+impl<'a> CatalogLookup<'a> {
+    /// Creates the lookup with `args` resolving the counts named in plural hints.
+    pub fn with_args(catalog: HashMap<String, String>, args: &'a dyn Lookup) -> Self {
+        CatalogLookup { catalog, args }
+    }
+}
+
+impl<'a> OwnedLookup for CatalogLookup<'a> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        let (id, hint) = split_spec(key);
+        let translation = match self.catalog.get(id) {
+            Some(translation) => translation,
+            None => return Some(id.to_string()),
+        };
+        let mut forms = translation.splitn(2, '|');
+        let singular = forms.next().unwrap_or("");
+        let plural = forms.next().unwrap_or(singular);
+        let count = hint.and_then(|hint| {
+            let count = self.args.lookup(hint).unwrap_or(hint);
+            count.trim().parse::<i64>().ok()
+        });
+        match count {
+            Some(count) if count != 1 => Some(plural.to_string()),
+            _ => Some(singular.to_string()),
+        }
+    }
+}
+
+/// Resolves keys by binary search over pairs sorted by key, a compact alternative to a map for
+/// large, static sets of values. The pairs are sorted once, when the lookup is created; of
+/// pairs with the same key, the first one given wins.
+pub struct SortedVecLookup {
+    pairs: Vec<(String, String)>,
+}
+
+impl SortedVecLookup {
+    pub fn new(mut pairs: Vec<(String, String)>) -> Self {
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        pairs.dedup_by(|later, earlier| later.0 == earlier.0);
+        SortedVecLookup { pairs }
+    }
+}
+
+impl Lookup for SortedVecLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let index = self
+            .pairs
+            .binary_search_by(|(candidate, _)| candidate.as_str().cmp(key))
+            .ok()?;
+        Some(&self.pairs[index].1)
+    }
+}
+
+/// Resolves keys by parsing them as `u32` and looking the number up in an integer-keyed map.
+/// Keys that are not numbers do not resolve.
+pub struct IntKeyedLookup {
+    values: HashMap<u32, String>,
+}
+
+impl IntKeyedLookup {
+    pub fn new(values: HashMap<u32, String>) -> Self {
+        IntKeyedLookup { values }
+    }
+}
+
+impl Lookup for IntKeyedLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let key: u32 = key.parse().ok()?;
+        self.values.get(&key).map(|value| value.as_str())
+    }
+}
+
+/// Resolves keys of the form `namespace.key` by passing `key` to the lookup registered for
+/// `namespace`. Keys without a `.` and unknown namespaces do not resolve.
+pub struct NamespacedLookup<'a> {
+    namespaces: HashMap<String, &'a dyn Lookup>,
+}
+
+impl<'a> NamespacedLookup<'a> {
+    pub fn new() -> Self {
+        NamespacedLookup {
+            namespaces: HashMap::new(),
+        }
+    }
+
+    /// Registers `lookup` for `namespace`, replacing any lookup registered for it before.
+    pub fn add(&mut self, namespace: &str, lookup: &'a dyn Lookup) -> &mut Self {
+        self.namespaces.insert(namespace.to_string(), lookup);
+        self
+    }
+}
+
+impl<'a> Default for NamespacedLookup<'a> {
+    fn default() -> Self {
+        NamespacedLookup::new()
+    }
+}
+
+impl<'a> Lookup for NamespacedLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let dot = key.find('.')?;
+        self.namespaces.get(&key[..dot])?.lookup(&key[dot + 1..])
+    }
+}
+
+/// Escapes resolved values for a specific output target.
+pub trait OutputEscaper {
+    fn escape(&self, value: &str) -> String;
+}
+
+/// Escapes values for use in HTML text and attribute values.
+pub struct HtmlEscaper;
+
+impl OutputEscaper for HtmlEscaper {
+    fn escape(&self, value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#39;"),
+                _ => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+/// Escapes values as single-quoted POSIX shell words.
+pub struct ShellEscaper;
+
+impl OutputEscaper for ShellEscaper {
+    fn escape(&self, value: &str) -> String {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Escapes values for use inside a JSON string literal, without the surrounding quotes.
+pub struct JsonEscaper;
+
+impl OutputEscaper for JsonEscaper {
+    fn escape(&self, value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                _ => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+/// Leaves values as they are.
+pub struct NoEscape;
+
+impl OutputEscaper for NoEscape {
+    fn escape(&self, value: &str) -> String {
+        value.to_string()
+    }
+}
+
+/// Produces a fresh value for `key` every time it is looked up, for instance a unique id per
+/// occurrence. Since the values are generated, this is an `OwnedLookup` and is used with
+/// `Template::render_owned`.
+pub struct GeneratorLookup<F> {
+    key: String,
+    generator: RefCell<F>,
+}
+
+impl<F: FnMut() -> String> GeneratorLookup<F> {
+    pub fn new(key: &str, generator: F) -> Self {
+        GeneratorLookup {
+            key: key.to_string(),
+            generator: RefCell::new(generator),
+        }
+    }
+}
+
+impl<F: FnMut() -> String> OwnedLookup for GeneratorLookup<F> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        if self.key == key {
+            Some((self.generator.borrow_mut())())
+        } else {
+            None
+        }
+    }
+}
+
+/// Wraps a lookup and logs every resolution at debug level to the given log target, noting
+/// whether the key was found. The resolved values are passed through unchanged.
+#[cfg(feature = "log")]
+pub struct LoggingLookup<'a> {
+    inner: &'a dyn Lookup,
+    target: &'a str,
+}
+
+#[cfg(feature = "log")]
+impl<'a> LoggingLookup<'a> {
+    pub fn new(inner: &'a dyn Lookup, target: &'a str) -> Self {
+        LoggingLookup { inner, target }
+    }
+}
+
+#[cfg(feature = "log")]
+impl<'a> Lookup for LoggingLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let value = self.inner.lookup(key);
+        if value.is_some() {
+            log::debug!(target: self.target, "lookup '{}': hit", key);
+        } else {
+            log::debug!(target: self.target, "lookup '{}': miss", key);
+        }
+        value
+    }
+}
+
+/// Rewrites each key with a regex replacement before resolving it from an inner lookup, e.g. to
+/// strip a prefix. Keys the regex does not match are passed through unchanged.
+#[cfg(feature = "regex")]
+pub struct RegexRewriteLookup<'a> {
+    pattern: regex::Regex,
+    replacement: &'a str,
+    inner: &'a dyn Lookup,
+}
+
+#[cfg(feature = "regex")]
+impl<'a> RegexRewriteLookup<'a> {
+    /// Creates the lookup; `replacement` may refer to capture groups as in `Regex::replace`.
+    pub fn new(pattern: regex::Regex, replacement: &'a str, inner: &'a dyn Lookup) -> Self {
+        RegexRewriteLookup {
+            pattern,
+            replacement,
+            inner,
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+impl<'a> Lookup for RegexRewriteLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let key = self.pattern.replace(key, self.replacement);
+        self.inner.lookup(&key)
+    }
+}
+
+/// Resolves numeric keys such as `1` from the numbered capture groups of a regex match and other
+/// keys from its named groups. Groups that did not participate in the match do not resolve, so
+/// with `Template::render_with_defaults`, `$group:fallback$` falls back to `fallback`.
+#[cfg(feature = "regex")]
+pub struct CapturesLookup<'a> {
+    captures: regex::Captures<'a>,
+}
+
+#[cfg(feature = "regex")]
+impl<'a> CapturesLookup<'a> {
+    pub fn new(captures: regex::Captures<'a>) -> Self {
+        CapturesLookup { captures }
+    }
+}
+
+#[cfg(feature = "regex")]
+impl<'a> Lookup for CapturesLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let group = match key.parse::<usize>() {
+            Ok(index) => self.captures.get(index),
+            Err(_) => self.captures.name(key),
+        };
+        group.map(|group| group.as_str())
+    }
+}
+
+/// Wraps a lookup and treats empty values as missing, so that they fall through in a chain.
+pub struct NonEmptyLookup<'a> {
+    inner: &'a dyn Lookup,
+}
+
+impl<'a> NonEmptyLookup<'a> {
+    pub fn new(inner: &'a dyn Lookup) -> Self {
+        NonEmptyLookup { inner }
+    }
+}
+
+impl<'a> Lookup for NonEmptyLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.inner.lookup(key).filter(|value| !value.is_empty())
+    }
+}
+
+/// Combinators available on every `Lookup`.
+pub trait LookupExt: Lookup + Sized {
+    /// Returns a lookup that calls `compute` with the key whenever this one misses it or
+    /// resolves it to the empty string. Use with `Template::render_owned`.
+    fn or_compute<F: Fn(&str) -> String>(self, compute: F) -> OrComputeLookup<Self, F> {
+        OrComputeLookup {
+            primary: self,
+            compute,
+        }
+    }
+}
+
+impl<L: Lookup> LookupExt for L {}
+
+/// A lookup falling back to a computed value, see `LookupExt::or_compute`.
+pub struct OrComputeLookup<L, F> {
+    primary: L,
+    compute: F,
+}
+
+impl<L: Lookup, F: Fn(&str) -> String> OwnedLookup for OrComputeLookup<L, F> {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        match self.primary.lookup(key) {
+            Some(value) if !value.is_empty() => Some(value.to_string()),
+            _ => Some((self.compute)(key)),
+        }
+    }
+}
+
+/// Caches the values of an inner lookup without keeping them alive: a cached value is served
+/// only while some caller still holds it, see `lookup_rc`, and is fetched from the inner lookup
+/// again once all of them have dropped it. Entries of dropped values are pruned whenever the
+/// cache has doubled in size since the last pruning.
+pub struct WeakCacheLookup<'a> {
+    inner: &'a dyn OwnedLookup,
+    cache: RefCell<HashMap<String, Weak<str>>>,
+    prune_at: Cell<usize>,
+}
+
+impl<'a> WeakCacheLookup<'a> {
+    pub fn new(inner: &'a dyn OwnedLookup) -> Self {
+        WeakCacheLookup {
+            inner,
+            cache: RefCell::new(HashMap::new()),
+            prune_at: Cell::new(WEAK_CACHE_MIN_PRUNE),
+        }
+    }
+
+    /// Resolves `key` to a shared value, from the cache if it is still alive.
+    pub fn lookup_rc(&self, key: &str) -> Option<Rc<str>> {
+        if let Some(value) = self.cache.borrow().get(key).and_then(Weak::upgrade) {
+            return Some(value);
+        }
+        let value: Rc<str> = Rc::from(self.inner.lookup_owned(key)?);
+        let mut cache = self.cache.borrow_mut();
+        cache.insert(key.to_string(), Rc::downgrade(&value));
+        if cache.len() >= self.prune_at.get() {
+            cache.retain(|_, value| value.strong_count() > 0);
+            self.prune_at.set((cache.len() * 2).max(WEAK_CACHE_MIN_PRUNE));
+        }
+        Some(value)
+    }
+}
+
+/// How many entries `WeakCacheLookup` holds, at least, before pruning dropped values.
+const WEAK_CACHE_MIN_PRUNE: usize = 16;
+
+/// Resolves numeric keys such as `$0$` to positional arguments and all other keys, as well as
+/// numeric keys past the last argument, from a lookup of named values.
+pub struct MixedLookup<'a> {
+    positional: &'a [&'a str],
+    named: &'a dyn Lookup,
+}
+
+impl<'a> MixedLookup<'a> {
+    pub fn new(positional: &'a [&'a str], named: &'a dyn Lookup) -> Self {
+        MixedLookup { positional, named }
+    }
+}
+
+impl<'a> Lookup for MixedLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        key.parse::<usize>()
+            .ok()
+            .and_then(|index| self.positional.get(index).copied())
+            .or_else(|| self.named.lookup(key))
+    }
+}
+
+/// Resolves keys from two lookups, only when they agree: a key the two resolve to different
+/// values does not resolve. A key only one of them resolves takes that value, unless the lookup
+/// is strict, in which case both must resolve it.
+pub struct ConsensusLookup<'a> {
+    first: &'a dyn Lookup,
+    second: &'a dyn Lookup,
+    strict: bool,
+}
+
+impl<'a> ConsensusLookup<'a> {
+    pub fn new(first: &'a dyn Lookup, second: &'a dyn Lookup) -> Self {
+        ConsensusLookup {
+            first,
+            second,
+            strict: false,
+        }
+    }
+
+    /// Requires both lookups to resolve a key for it to resolve.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+impl<'a> Lookup for ConsensusLookup<'a> {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        match (self.first.lookup(key), self.second.lookup(key)) {
+            (Some(first), Some(second)) if first == second => Some(first),
+            (Some(value), None) | (None, Some(value)) if !self.strict => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves a configured key to the current time, formatted by the chrono format string written
+/// after a `:` as in `$now:%Y-%m-%d$`, or as RFC 3339 without one. Other keys and invalid
+/// format strings do not resolve. Use with `Template::render_owned`.
+#[cfg(feature = "chrono")]
+pub struct DateTimeLookup {
+    key: String,
+    clock: Box<dyn Fn() -> chrono::DateTime<chrono::Utc>>,
+}
+
+#[cfg(feature = "chrono")]
+impl DateTimeLookup {
+    pub fn new(key: &str) -> Self {
+        DateTimeLookup::with_clock(key, chrono::Utc::now)
+    }
+
+    /// Creates the lookup with `clock` providing the current time, e.g. a fixed one for tests.
+    pub fn with_clock<F>(key: &str, clock: F) -> Self
+    where
+        F: Fn() -> chrono::DateTime<chrono::Utc> + 'static,
+    {
+        DateTimeLookup {
+            key: key.to_string(),
+            clock: Box::new(clock),
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl OwnedLookup for DateTimeLookup {
+    fn lookup_owned(&self, key: &str) -> Option<String> {
+        let (name, format) = split_spec(key);
+        if name != self.key {
+            return None;
+        }
+        let now = (self.clock)();
+        match format {
+            Some(format) => {
+                let items: Vec<_> = chrono::format::StrftimeItems::new(format).collect();
+                if items.contains(&chrono::format::Item::Error) {
+                    return None;
+                }
+                Some(now.format_with_items(items.into_iter()).to_string())
+            }
+            None => Some(now.to_rfc3339()),
+        }
+    }
+}
+
+/// Resolves keys from the parameters of a URL query string such as `a=1&b=two%20words`, decoding
+/// percent escapes and `+` as a space in both keys and values. When a key repeats, its first
+/// value wins.
+#[cfg(feature = "percent-encoding")]
+pub struct QueryLookup {
+    params: HashMap<String, String>,
+}
+
+#[cfg(feature = "percent-encoding")]
+impl QueryLookup {
+    pub fn parse(query: &str) -> Self {
+        let decode = |text: &str| {
+            let text = text.replace('+', " ");
+            percent_encoding::percent_decode_str(&text)
+                .decode_utf8_lossy()
+                .into_owned()
+        };
+        let mut params = HashMap::new();
+        for pair in query.trim_start_matches('?').split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            let (key, value) = match pair.find('=') {
+                Some(index) => (&pair[..index], &pair[index + 1..]),
+                None => (pair, ""),
+            };
+            params.entry(decode(key)).or_insert_with(|| decode(value));
+        }
+        QueryLookup { params }
+    }
+}
+
+#[cfg(feature = "percent-encoding")]
+impl Lookup for QueryLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(|value| value.as_str())
+    }
+}
+
+/// Resolves keys from the environment variables of the process. The environment is read once,
+/// when the lookup is created, so later changes to it are not seen.
+pub struct EnvLookup {
+    vars: HashMap<String, String>,
+}
+
+impl EnvLookup {
+    pub fn new() -> Self {
+        let vars = env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        EnvLookup { vars }
+    }
+}
+
+impl Default for EnvLookup {
+    fn default() -> Self {
+        EnvLookup::new()
+    }
+}
+
+impl Lookup for EnvLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(|value| value.as_str())
+    }
+}
+
+/// Resolves keys from the environment variables whose names start with a prefix, so that only
+/// those are exposed: with the prefix `APP_`, `$DATABASE_URL$` reads `APP_DATABASE_URL`. Like
+/// `EnvLookup`, the environment is read once, when the lookup is created.
+pub struct PrefixedEnvLookup {
+    vars: HashMap<String, String>,
+}
+
+impl PrefixedEnvLookup {
+    pub fn new(prefix: &str) -> Self {
+        let vars = env::vars_os()
+            .filter_map(|(key, value)| {
+                let key = key.into_string().ok()?;
+                let key = key.strip_prefix(prefix)?.to_string();
+                Some((key, value.into_string().ok()?))
+            })
+            .collect();
+        PrefixedEnvLookup { vars }
+    }
+}
+
+impl Lookup for PrefixedEnvLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(|value| value.as_str())
+    }
+}
+
+/// Resolves column names to the cells of one row of tabular data, such as a CSV file, given the
+/// header row. Columns the row is too short for do not resolve.
+pub struct CsvRowLookup {
+    header: Vec<String>,
+    row: Vec<String>,
+}
+
+impl CsvRowLookup {
+    pub fn new(header: Vec<String>, row: Vec<String>) -> Self {
+        CsvRowLookup { header, row }
+    }
+}
+
+impl Lookup for CsvRowLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let column = self.header.iter().position(|name| name == key)?;
+        self.row.get(column).map(|cell| cell.as_str())
+    }
+}
+
+/// Resolves keys from environment variables, falling back to a map of defaults for keys that
+/// are not set in the environment.
+pub struct ConfigLookup {
+    env: EnvLookup,
+    defaults: HashMap<String, String>,
+}
+
+impl ConfigLookup {
+    pub fn new(defaults: HashMap<String, String>) -> Self {
+        ConfigLookup {
+            env: EnvLookup::new(),
+            defaults,
+        }
+    }
+}
+
+impl Lookup for ConfigLookup {
+    fn lookup(&self, key: &str) -> Option<&str> {
+        self.env
+            .lookup(key)
+            .or_else(|| self.defaults.get(key).map(|value| value.as_str()))
+    }
+}
+
+/// A reusable output buffer for `Template::render_reusing`, saving an allocation per render when
+/// rendering many times in a row.
+#[derive(Debug, Default)]
+pub struct RenderBuffer {
+    buffer: String,
+}
+
+impl RenderBuffer {
+    pub fn new() -> Self {
+        RenderBuffer::default()
+    }
+
+    /// Returns the output of the last render.
+    pub fn as_str(&self) -> &str {
+        &self.buffer
+    }
+}
+
+/// A template that remembers its last output, for rendering repeatedly against a lookup that
+/// rarely changes. The caller bumps a generation counter whenever the lookup changes.
+pub struct CachedTemplate {
+    template: Template,
+    cached: Option<(u64, String)>,
+}
+
+impl CachedTemplate {
+    pub fn new(template: Template) -> Self {
+        CachedTemplate {
+            template,
+            cached: None,
+        }
+    }
+
+    /// Returns the wrapped template.
+    pub fn template(&self) -> &Template {
+        &self.template
+    }
+
+    /// Renders the template against `lookup`, unless the last output was rendered for the same
+    /// `generation`, in which case that output is returned as is.
+    pub fn render_cached(&mut self, lookup: &dyn Lookup, generation: u64) -> &str {
+        match self.cached {
+            Some((cached, _)) if cached == generation => (),
+            _ => self.cached = Some((generation, self.template.render(lookup))),
+        }
+        self.cached.as_ref().map_or("", |(_, output)| output)
+    }
+}
+
+/// Receives the fragments of a template in order, see `Template::accept`.
+pub trait Visitor {
+    fn visit_constant(&mut self, text: &str);
+    fn visit_variable(&mut self, name: &str);
+}
+
+/// Decides how each fragment is written, for rendering with `Template::render_strategy`: how
+/// constants are written, and how variables are, resolved or not.
+pub trait RenderStrategy {
+    fn write_constant(&self, out: &mut String, text: &str);
+    fn write_variable(&self, out: &mut String, name: &str, value: Option<&str>);
+}
+
+/// A template rendered against a lookup on demand, writing straight to the formatter when
+/// displayed. See `Template::display_with`.
+pub struct Rendered<'a> {
+    template: &'a Template,
+    lookup: &'a dyn Lookup,
+}
+
+impl<'a> fmt::Display for Rendered<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for fragment in &self.template.fragments {
+            match fragment {
+                Fragment::Constant(text) => f.write_str(text)?,
+                Fragment::Variable(var) => if let Some(text) = self.lookup.lookup(var) {
+                    f.write_str(text)?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The output of `Template::render_stats`, along with how much of it came from constants and
+/// how much from variables.
+#[derive(Debug, PartialEq)]
+pub struct RenderStats {
+    pub output: String,
+    pub constant_bytes: usize,
+    pub variable_bytes: usize,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Debug)]
+pub enum ParseError {
+    UNTERMINATED_VARIABLE,
+    /// A line of dotenv text is not of the form `KEY=VALUE`; lines are counted from 1.
+    InvalidLine { line: usize },
+    /// A variable name is empty, as in `${}`, or contains characters other than letters, digits
+    /// and `_`, which `ParseOptions::word_names` disallows.
+    InvalidVariableName { name: String },
+    /// A `${` opened at byte `offset` has no closing `}`.
+    UnterminatedBrace { offset: usize },
+    /// A variable appears more than once, which `ParseOptions::unique_variables` disallows.
+    DuplicateVariable { name: String },
+    /// The variable opened at byte `offset` has a name of `len` bytes, more than
+    /// `ParseOptions::max_name_len` allows.
+    VariableNameTooLong { offset: usize, len: usize },
+    /// A `$$` escape at byte `offset`, which `ParseOptions::disallow_escape` disallows.
+    UnexpectedEscape { offset: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UNTERMINATED_VARIABLE => write!(f, "unterminated variable"),
+            ParseError::InvalidLine { line } => write!(f, "invalid line {}", line),
+            ParseError::InvalidVariableName { name } => {
+                write!(f, "invalid variable name '{}'", name)
+            }
+            ParseError::UnterminatedBrace { offset } => {
+                write!(f, "unterminated brace at byte {}", offset)
+            }
+            ParseError::DuplicateVariable { name } => {
+                write!(f, "duplicate variable '{}'", name)
+            }
+            ParseError::VariableNameTooLong { offset, len } => {
+                write!(f, "variable name at byte {} is too long ({} bytes)", offset, len)
+            }
+            ParseError::UnexpectedEscape { offset } => {
+                write!(f, "unexpected escape at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// The error returned when a template cannot be loaded, covering both reading and parsing.
+#[derive(Debug)]
+pub enum TemplateError {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    Parse(ParseError),
+    /// The template was given as UTF-16 that does not decode, see `Template::from_utf16`.
+    Utf16(FromUtf16Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            TemplateError::Io(error) => write!(f, "failed to read template: {}", error),
+            TemplateError::Parse(error) => write!(f, "failed to parse template: {}", error),
+            TemplateError::Utf16(error) => write!(f, "failed to decode template: {}", error),
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            TemplateError::Io(error) => Some(error),
+            TemplateError::Parse(error) => Some(error),
+            TemplateError::Utf16(error) => Some(error),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for TemplateError {
+    fn from(error: io::Error) -> Self {
+        TemplateError::Io(error)
+    }
+}
+
+impl From<ParseError> for TemplateError {
+    fn from(error: ParseError) -> Self {
+        TemplateError::Parse(error)
+    }
+}
+
+impl From<FromUtf16Error> for TemplateError {
+    fn from(error: FromUtf16Error) -> Self {
+        TemplateError::Utf16(error)
+    }
+}
+
+/// The error returned by the rendering methods that can fail.
+#[derive(PartialEq, Debug)]
+pub enum RenderError {
+    /// The named variable could not be resolved.
+    MissingVariable(String),
+    /// More variables were resolved than the budget allowed.
+    BudgetExceeded,
+    /// Rendering nested templates went deeper than allowed.
+    RecursionLimit,
+    /// A variable referred to a filter that is not registered.
+    UnknownFilter(String),
+    /// The lookup panicked while resolving the named variable.
+    LookupPanicked(String),
+    /// The output would have grown beyond the allowed size.
+    OutputTooLarge,
+    /// A variable expanded to itself, directly or through others; the path of names from the
+    /// first occurrence of the variable to its repetition.
+    Cycle(Vec<String>),
+    /// More distinct variables than allowed could not be resolved; the number of them.
+    TooManyMissing(usize),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::MissingVariable(name) => write!(f, "missing variable '{}'", name),
+            RenderError::BudgetExceeded => write!(f, "substitution budget exceeded"),
+            RenderError::RecursionLimit => write!(f, "recursion limit reached"),
+            RenderError::UnknownFilter(name) => write!(f, "unknown filter '{}'", name),
+            RenderError::LookupPanicked(name) => {
+                write!(f, "lookup panicked resolving '{}'", name)
+            }
+            RenderError::OutputTooLarge => write!(f, "output too large"),
+            RenderError::Cycle(path) => write!(f, "variable cycle {}", path.join(" -> ")),
+            RenderError::TooManyMissing(count) => write!(f, "{} variables missing", count),
+        }
+    }
+}
+
+impl Error for RenderError {}
+
+/// The difference between a rendered template and its expected output, reported by
+/// `Template::assert_render`.
+#[derive(PartialEq, Debug)]
+pub struct RenderMismatch {
+    /// The byte offset of the first difference.
+    pub offset: usize,
+    /// The expected text around the difference.
+    pub expected: String,
+    /// The rendered text around the difference.
+    pub actual: String,
+}
+
+impl fmt::Display for RenderMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "output differs at byte {}: expected {:?}, got {:?}",
+            self.offset, self.expected, self.actual
+        )
+    }
+}
+
+impl Error for RenderMismatch {}
+
+/// The number of bytes of context `RenderMismatch` shows on each side of a difference.
+const MISMATCH_CONTEXT: usize = 16;
+
+/// How deep `Template::render_recursive` expands values before giving up.
+const MAX_RECURSION_DEPTH: usize = 64;
+
+/// How many threads `Template::render_parallel` resolves variables on, at most.
+#[cfg(feature = "std")]
+const MAX_PARALLEL_LOOKUPS: usize = 16;
+
+/// Returns the text of `text` around `offset`, widened to character boundaries.
+fn context_window(text: &str, offset: usize) -> String {
+    let mut start = offset.saturating_sub(MISMATCH_CONTEXT).min(text.len());
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (offset + MISMATCH_CONTEXT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    text[start..end].to_string()
+}
+
+/// A named value transformation, see `Template::render_with_filters`.
+pub type Filter = Box<dyn Fn(&str) -> String>;
+
+/// A fragment of a template as exchanged by `Template::into_fragments` and
+/// `Template::from_fragments`. Constant text is held as it renders, without any escaping.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PublicFragment {
+    Constant(String),
+    Variable(String),
+}
+
+/// A piece of rendered output as returned by `Template::render_tokens`, telling constant text
+/// apart from resolved values.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Token {
+    Literal(String),
+    /// A variable and the value it resolved to, if any.
+    Value { name: String, value: Option<String> },
+}
+
+/// A run of a template as returned by `Template::chunks`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Chunk {
+    /// Consecutive constant text, concatenated.
+    Literal(String),
+    /// A single variable, by name.
+    Variable(String),
+}
+
+/// Options controlling how `Template::with_options` parses a spec. The default options parse
+/// exactly like `Template::new`.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    lenient: bool,
+    whitespace_control: bool,
+    nested_defaults: bool,
+    word_names: bool,
+    smart_dollars: bool,
+    terminators: Vec<char>,
+    braces: bool,
+    unique_variables: bool,
+    max_name_len: Option<usize>,
+    line_comments: bool,
+    disallow_escape: bool,
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        ParseOptions::default()
+    }
+
+    /// Treats an unterminated variable at the end of the spec as literal text.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Strips the whitespace next to variables marked with a `-`, Jinja style: `$-name$` trims
+    /// the whitespace before the variable, `$name-$` the whitespace after it and `$-name-$` both.
+    /// The markers are not part of the variable name.
+    pub fn whitespace_control(mut self, whitespace_control: bool) -> Self {
+        self.whitespace_control = whitespace_control;
+        self
+    }
+
+    /// Allows the default of a variable, written after a `:`, to contain variables of its own, as
+    /// in `$name:$fallback$$`. Within a default, a `$` followed by a name character opens a
+    /// nested variable and any other `$` closes the enclosing one. See
+    /// `Template::render_with_defaults`.
+    pub fn nested_defaults(mut self, nested_defaults: bool) -> Self {
+        self.nested_defaults = nested_defaults;
+        self
+    }
+
+    /// Requires variable names to consist of letters, digits and `_` only, so that a stray `$`
+    /// cannot capture a long stretch of text as a name. Whitespace control markers are allowed.
+    pub fn word_names(mut self, word_names: bool) -> Self {
+        self.word_names = word_names;
+        self
+    }
+
+    /// Opens a variable only at a `$` directly followed by a letter or `_`, or a `-` marker with
+    /// whitespace control, taking any other `$` literally, so that `$5.00` needs no escaping.
+    /// `$$` still stands for a literal `$`.
+    pub fn smart_dollars(mut self, smart_dollars: bool) -> Self {
+        self.smart_dollars = smart_dollars;
+        self
+    }
+
+    /// Lets any of `terminators`, besides `$`, close a variable, as in `$name` followed by a
+    /// newline. Whichever closing character comes first ends the variable; a terminator is kept
+    /// as text, while a closing `$` is consumed. A `$` directly followed by a terminator is
+    /// taken literally, and `$$` still stands for a literal `$`. With `\n` as a terminator, a
+    /// `\r\n` line ending closes a variable as a whole.
+    pub fn terminators(mut self, terminators: &[char]) -> Self {
+        self.terminators = terminators.to_vec();
+        self
+    }
+
+    /// Additionally accepts variables written as `${name}`, which may sit next to `$name$`
+    /// variables in the same spec. Braces within the name must balance, as in `${a{b}}`, or be
+    /// escaped with a backslash, as in `${a\}b}`, which also escapes a backslash.
+    pub fn braces(mut self, braces: bool) -> Self {
+        self.braces = braces;
+        self
+    }
+
+    /// Rejects specs in which a variable name appears more than once.
+    pub fn unique_variables(mut self, unique_variables: bool) -> Self {
+        self.unique_variables = unique_variables;
+        self
+    }
+
+    /// Rejects variable names longer than `max_name_len` bytes, guarding against untrusted specs
+    /// with absurdly long names.
+    pub fn max_name_len(mut self, max_name_len: usize) -> Self {
+        self.max_name_len = Some(max_name_len);
+        self
+    }
+
+    /// Drops line comments, which start with `$//$` and run up to, but not including, the end of
+    /// the line, be it `\n` or `\r\n`.
+    pub fn line_comments(mut self, line_comments: bool) -> Self {
+        self.line_comments = line_comments;
+        self
+    }
+
+    /// Rejects `$$` with `ParseError::UnexpectedEscape` instead of taking it as a literal `$`, to
+    /// catch accidentally doubled dollars.
+    pub fn disallow_escape(mut self, disallow_escape: bool) -> Self {
+        self.disallow_escape = disallow_escape;
+        self
+    }
+
+    fn check_name_len(&self, name: &str, offset: usize) -> Result<(), ParseError> {
+        match self.max_name_len {
+            Some(max) if name.len() > max => Err(ParseError::VariableNameTooLong {
+                offset,
+                len: name.len(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A pool of interned variable names. Templates store each distinct variable name once, and
+/// templates parsed with a shared pool (see `Template::with_pool`) share the names across each
+/// other as well.
+#[derive(Debug, Default)]
+pub struct NamePool {
+    names: HashSet<Arc<str>>,
+}
+
+impl NamePool {
+    pub fn new() -> Self {
+        NamePool::default()
+    }
+
+    fn intern(&mut self, name: &str) -> Arc<str> {
+        if let Some(interned) = self.names.get(name) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = Arc::from(name);
+        self.names.insert(interned.clone());
+        interned
+    }
+}
+
+/// Whether a `\` before `c` within a variable name escapes it.
+fn is_escaped(c: char) -> bool {
+    c == '$' || c == '\\'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Removes the whitespace control markers from variable names, trimming the neighbouring
+/// constants accordingly.
+fn trim_around_markers(fragments: &mut Vec<Fragment>, pool: &mut NamePool) {
+    for index in 0..fragments.len() {
+        let (trim_before, trim_after) = match fragments[index] {
+            Fragment::Variable(ref mut var) => {
+                let mut name: &str = var;
+                let trim_before = name.len() > 1 && name.starts_with('-');
+                if trim_before {
+                    name = &name[1..];
+                }
+                let trim_after = name.len() > 1 && name.ends_with('-');
+                if trim_after {
+                    name = &name[..name.len() - 1];
+                }
+                if trim_before || trim_after {
+                    *var = pool.intern(name);
+                }
+                (trim_before, trim_after)
+            }
+            _ => continue,
+        };
+        if trim_before && index > 0 {
+            if let Fragment::Constant(ref mut text) = fragments[index - 1] {
+                let len = text.trim_end().len();
+                text.to_mut().truncate(len);
+            }
+        }
+        if trim_after && index + 1 < fragments.len() {
+            if let Fragment::Constant(ref mut text) = fragments[index + 1] {
+                *text = Text::from(text.trim_start().to_string());
+            }
+        }
+    }
+    fragments.retain(|fragment| match fragment {
+        Fragment::Constant(text) => !text.is_empty(),
+        _ => true,
+    });
+}
+
+/// Splits a variable into its name and the inline spec following the first `:`, if any.
+fn split_spec(variable: &str) -> (&str, Option<&str>) {
+    match variable.find(':') {
+        Some(index) => (&variable[..index], Some(&variable[index + 1..])),
+        None => (variable, None),
+    }
+}
+
+/// Formats `value` as a number according to a pattern such as `#,###` or `#,###.00`: the
+/// character before the last run of `#` and `0` groups the integer digits, and the digits after
+/// a `.` give the number of decimal places. Returns `None` for non-numbers and unknown patterns.
+#[cfg(feature = "num-format")]
+fn format_number(value: &str, pattern: &str) -> Option<String> {
+    let is_digit = |c: char| c == '#' || c == '0';
+    let (integer_pattern, decimals) = match pattern.split_once('.') {
+        Some((integer, fraction)) if fraction.chars().all(is_digit) => (integer, fraction.len()),
+        Some(_) => return None,
+        None => (pattern, 0),
+    };
+    let group = match integer_pattern.rfind(|c: char| !is_digit(c)) {
+        Some(index) => {
+            let separator = integer_pattern[index..].chars().next()?;
+            let size = integer_pattern[index + separator.len_utf8()..].len();
+            if size == 0 || !integer_pattern[..index].chars().all(is_digit) {
+                return None;
+            }
+            Some((separator, size))
+        }
+        None => None,
+    };
+    let value = value.trim();
+    let digits = match value.parse::<i128>() {
+        Ok(number) if decimals == 0 => number.to_string(),
+        _ => {
+            let number = value.parse::<f64>().ok().filter(|number| number.is_finite())?;
+            format!("{:.*}", decimals, number)
+        }
+    };
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", &digits[..]),
+    };
+    let (integer, fraction) = match digits.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (digits, None),
+    };
+    let mut result = String::with_capacity(digits.len() * 2);
+    result.push_str(sign);
+    for (index, c) in integer.chars().enumerate() {
+        if let Some((separator, size)) = group {
+            if index > 0 && (integer.len() - index) % size == 0 {
+                result.push(separator);
+            }
+        }
+        result.push(c);
+    }
+    if let Some(fraction) = fraction {
+        result.push('.');
+        result.push_str(fraction);
+    }
+    Some(result)
+}
+
+impl Template {
+    fn with_fragments(fragments: Vec<Fragment>) -> Template {
+        let has_variables = fragments
+            .iter()
+            .any(|fragment| matches!(fragment, Fragment::Variable(_)));
+        let size_hint = fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Constant(text) => text.len(),
+                _ => 0,
+            })
+            .sum();
+        Template {
+            fragments,
+            has_variables,
+            size_hint,
+            spec: OnceLock::new(),
+            defaults: HashMap::new(),
+        }
+    }
+
+    /// Builds a template from fragments derived from this template's, keeping the parsed
+    /// defaults of the variables that remain.
+    fn derive(&self, fragments: Vec<Fragment>) -> Template {
+        let mut template = Template::with_fragments(fragments);
+        for fragment in &template.fragments {
+            if let Fragment::Variable(var) = fragment {
+                if let Some(default) = self.defaults.get(var) {
+                    template.defaults.insert(var.clone(), default.clone());
+                }
+            }
+        }
+        template
+    }
+
+    /// Returns a template without any fragments, rendering as the empty string.
+    pub fn empty() -> Template {
+        Template::with_fragments(Vec::new())
+    }
+
+    /// Parses a template spec, where variables are written as `$name$` and a literal `$` as `$$`.
+    /// A lone `$` at the very end of the spec has no name to start and is taken literally. Within
+    /// a name, `\$` stands for a `$` that does not close the variable, as in `$foo\$bar$`, and
+    /// `\\` for a backslash.
+    pub fn new(spec: &str) -> Result<Template, ParseError> {
+        Template::with_options(spec, &ParseOptions::new())
+    }
+
+    /// Parses a template like `new`, but treats an unterminated variable at the end of the spec
+    /// as literal text, including its opening `$`, instead of failing.
+    pub fn new_lenient(spec: &str) -> Result<Template, ParseError> {
+        Template::with_options(spec, &ParseOptions::new().lenient(true))
+    }
+
+    /// Returns whether `new` would accept `spec`, without allocating any fragments.
+    pub fn is_valid(spec: &str) -> bool {
+        let mut mode = Mode::Constant;
+        let mut empty = true;
+        let mut chars = spec.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if mode == Mode::Variable && chars.peek().is_some_and(|&c| is_escaped(c)) => {
+                    chars.next();
+                    empty = false;
+                }
+                '$' => match mode {
+                    Mode::Constant => {
+                        mode = Mode::Variable;
+                        empty = true;
+                    }
+                    Mode::Variable => {
+                        mode = Mode::Constant;
+                        empty = !empty;
+                    }
+                },
+                _ => empty = false,
+            }
+        }
+        mode == Mode::Constant || empty
+    }
+
+    /// Checks that every variable in `spec` is closed, without parsing it, returning the offset
+    /// of the first delimiter left open along with the delimiter itself. Meant for live feedback
+    /// while a spec is edited.
+    pub fn check_balance(spec: &str) -> Result<(), (usize, char)> {
+        let mut opened = None;
+        let mut empty = true;
+        let mut chars = spec.char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '\\' if opened.is_some()
+                    && chars.peek().is_some_and(|&(_, next)| is_escaped(next)) =>
+                {
+                    chars.next();
+                    empty = false;
+                }
+                '$' => match opened {
+                    None => {
+                        opened = Some(offset);
+                        empty = true;
+                    }
+                    Some(_) => {
+                        opened = None;
+                        empty = !empty;
+                    }
+                },
+                _ => empty = false,
+            }
+        }
+        match opened {
+            Some(offset) if !empty => Err((offset, '$')),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads and parses the template spec stored in the file at `path`.
+    #[cfg(feature = "std")]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Template, TemplateError> {
+        let spec = fs::read_to_string(path)?;
+        Ok(Template::new(&spec)?)
+    }
+
+    /// Parses a template spec as configured by `options`.
+    pub fn with_options(spec: &str, options: &ParseOptions) -> Result<Template, ParseError> {
+        Template::with_pool(spec, options, &mut NamePool::new())
+    }
+
+    /// Parses a template spec like `with_options`, interning the variable names in `pool` so that
+    /// they are shared with all other templates parsed with the same pool.
+    pub fn with_pool(
+        spec: &str,
+        options: &ParseOptions,
+        pool: &mut NamePool,
+    ) -> Result<Template, ParseError> {
+        let mut result = Vec::new();
+        let mut buf = String::new();
+        let mut mode = Mode::Constant;
+        // For each level of nesting within the current variable, whether it has a default.
+        let mut defaulted = vec![false];
+        // The offset of the `$` opening the current variable.
+        let mut opened = 0;
+        let mut chars = spec.char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            if mode == Mode::Constant
+                && c == '$'
+                && options.braces
+                && chars.peek().is_some_and(|&(_, next)| next == '{')
+            {
+                chars.next();
+                let mut name = String::new();
+                let mut depth = 0;
+                loop {
+                    match chars.next() {
+                        Some((_, '}')) if depth == 0 => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => name.push(escaped),
+                            None => return Err(ParseError::UnterminatedBrace { offset }),
+                        },
+                        Some((_, c)) => {
+                            match c {
+                                '{' => depth += 1,
+                                '}' => depth -= 1,
+                                _ => (),
+                            }
+                            name.push(c);
+                        }
+                        None => return Err(ParseError::UnterminatedBrace { offset }),
+                    }
+                }
+                if name.is_empty() {
+                    return Err(ParseError::InvalidVariableName { name });
+                }
+                if !buf.is_empty() {
+                    result.push(Fragment::Constant(std::mem::take(&mut buf).into()));
+                }
+                options.check_name_len(&name, offset)?;
+                result.push(Fragment::Variable(pool.intern(&name)));
+                continue;
+            }
+            if mode == Mode::Constant && c == '$' && options.smart_dollars {
+                let opens = chars.peek().is_some_and(|&(_, next)| {
+                    next.is_alphabetic()
+                        || next == '_'
+                        || (options.whitespace_control && next == '-')
+                });
+                if !opens {
+                    if chars.peek().is_some_and(|&(_, next)| next == '$') {
+                        if options.disallow_escape {
+                            return Err(ParseError::UnexpectedEscape { offset });
+                        }
+                        chars.next();
+                    }
+                    buf.push(c);
+                    continue;
+                }
+            }
+            if mode == Mode::Variable && options.nested_defaults {
+                match c {
+                    ':' => if let Some(has_default) = defaulted.last_mut() {
+                        *has_default = true;
+                    },
+                    '$' if defaulted.last() == Some(&true)
+                        && chars.peek().is_some_and(|&(_, next)| is_name_char(next)) =>
+                    {
+                        defaulted.push(false);
+                        buf.push(c);
+                        continue;
+                    }
+                    '$' if defaulted.len() > 1 => {
+                        defaulted.pop();
+                        buf.push(c);
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+            match c {
+                '\\' if mode == Mode::Variable
+                    && chars.peek().is_some_and(|&(_, next)| is_escaped(next)) =>
+                {
+                    if let Some((_, escaped)) = chars.next() {
+                        buf.push(escaped);
+                    }
+                }
+                '$' => match mode {
+                    Mode::Constant if !buf.is_empty() => {
+                        result.push(Fragment::Constant(std::mem::take(&mut buf).into()));
+                        mode = Mode::Variable;
+                        defaulted = vec![false];
+                        opened = offset;
+                    }
+                    Mode::Variable if buf.is_empty() && options.disallow_escape => {
+                        return Err(ParseError::UnexpectedEscape { offset: opened });
+                    }
+                    Mode::Variable if buf.is_empty() => {
+                        // An escaped `$` continues the preceding constant, if any.
+                        if let Some(Fragment::Constant(text)) = result.last_mut() {
+                            buf = std::mem::take(text).into_string();
+                            result.pop();
+                        }
+                        buf.push(c);
+                        mode = Mode::Constant;
+                    }
+                    Mode::Variable if options.line_comments && buf == "//" => {
+                        // A comment continues the preceding constant after the end of the line.
+                        buf.clear();
+                        if let Some(Fragment::Constant(text)) = result.last_mut() {
+                            buf = std::mem::take(text).into_string();
+                            result.pop();
+                        }
+                        while let Some(&(at, next)) = chars.peek() {
+                            if next == '\n' || spec[at..].starts_with("\r\n") {
+                                break;
+                            }
+                            chars.next();
+                        }
+                        mode = Mode::Constant;
+                    }
+                    Mode::Variable => {
+                        options.check_name_len(&buf, opened)?;
+                        result.push(Fragment::Variable(pool.intern(&buf)));
+                        buf.clear();
+                        mode = Mode::Constant;
+                    }
+                    _ => {
+                        mode = Mode::Variable;
+                        defaulted = vec![false];
+                        opened = offset;
+                    }
+                },
+                _ if mode == Mode::Variable && options.terminators.contains(&c) => {
+                    // A `\r` before a terminating `\n` belongs to the line ending.
+                    let crlf = c == '\n' && buf.ends_with('\r');
+                    if crlf {
+                        buf.pop();
+                    }
+                    if buf.is_empty() {
+                        if let Some(Fragment::Constant(text)) = result.last_mut() {
+                            buf = std::mem::take(text).into_string();
+                            result.pop();
+                        }
+                        buf.push('$');
+                    } else {
+                        options.check_name_len(&buf, opened)?;
+                        result.push(Fragment::Variable(pool.intern(&buf)));
+                        buf.clear();
+                    }
+                    if crlf {
+                        buf.push('\r');
+                    }
+                    buf.push(c);
+                    mode = Mode::Constant;
+                }
+                _ => buf.push(c),
+            }
+        }
+
+        // A `$` ending the spec is literal text rather than an unterminated variable, as is any
+        // unterminated variable when parsing leniently.
+        if mode == Mode::Variable && (options.lenient || buf.is_empty()) {
+            buf.insert(0, '$');
+            match result.last_mut() {
+                Some(Fragment::Constant(text)) => text.to_mut().push_str(&buf),
+                _ => result.push(Fragment::Constant(buf.into())),
+            }
+        } else if mode == Mode::Variable {
+            return Err(ParseError::UNTERMINATED_VARIABLE);
+        } else if !buf.is_empty() {
+            result.push(Fragment::Constant(buf.into()));
+        }
+
+        if options.whitespace_control {
+            trim_around_markers(&mut result, pool);
+        }
+        if options.word_names {
+            for fragment in &result {
+                match fragment {
+                    Fragment::Variable(var) if !var.chars().all(is_name_char) => {
+                        return Err(ParseError::InvalidVariableName {
+                            name: var.to_string(),
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if options.unique_variables {
+            let mut seen = HashSet::new();
+            for fragment in &result {
+                if let Fragment::Variable(var) = fragment {
+                    if !seen.insert(var) {
+                        return Err(ParseError::DuplicateVariable {
+                            name: var.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        let mut template = Template::with_fragments(result);
+        if options.nested_defaults {
+            for fragment in &template.fragments {
+                if let Fragment::Variable(var) = fragment {
+                    if let (_, Some(default)) = split_spec(var) {
+                        let default = Template::with_pool(default, options, pool)?;
+                        template.defaults.insert(var.clone(), default);
+                    }
+                }
+            }
+        }
+        Ok(template)
+    }
+
+    pub fn set(self, key: &str, value: &str) -> Template {
+        self.partial(&SingleLookup::new(key, value))
+    }
+
+    /// Substitutes `value` for the variable `key` like `set`, but moves `value` into the template
+    /// instead of copying it, copying it only for the occurrences of `key` besides the last.
+    pub fn set_owned(mut self, key: &str, value: String) -> Template {
+        let mut fragments = std::mem::take(&mut self.fragments);
+        let mut positions = fragments
+            .iter()
+            .enumerate()
+            .filter(|(_, fragment)| matches!(fragment, Fragment::Variable(var) if **var == *key))
+            .map(|(index, _)| index)
+            .collect::<Vec<usize>>();
+        if let Some(last) = positions.pop() {
+            for index in positions {
+                fragments[index] = Fragment::Constant(value.clone().into());
+            }
+            fragments[last] = Fragment::Constant(value.into());
+        }
+        self.derive(fragments)
+    }
+
+    pub fn partial(&self, lookup: &dyn Lookup) -> Template {
+        self.partial_filtered(lookup, |_| true)
+    }
+
+    /// Substitutes every variable resolved by `lookup` except those named in `keep`, which are
+    /// left as variables for a later render.
+    pub fn partial_except(&self, lookup: &dyn Lookup, keep: &HashSet<String>) -> Template {
+        self.partial_filtered(lookup, |var| !keep.contains(var))
+    }
+
+    /// Substitutes only the variables named in `names`, leaving all others as variables even when
+    /// `lookup` could resolve them.
+    pub fn partial_only(&self, lookup: &dyn Lookup, names: &HashSet<String>) -> Template {
+        self.partial_filtered(lookup, |var| names.contains(var))
+    }
+
+    /// Substitutes only the variables whose names satisfy `pred`, leaving the rest in place.
+    pub fn partial_where<F>(&self, lookup: &dyn Lookup, pred: F) -> Template
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.partial_filtered(lookup, pred)
+    }
+
+    /// Substitutes every variable `lookup` resolves like `partial`, but stores the value of a
+    /// variable once, shared by all of its occurrences, instead of copying it for each.
+    pub fn partial_shared(&self, lookup: &dyn Lookup) -> Template {
+        let mut shared: HashMap<&str, Arc<str>> = HashMap::new();
+        let fragments = self
+            .fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Variable(var) => match shared.get(&**var) {
+                    Some(value) => Fragment::Constant(Text::Shared(value.clone())),
+                    None => match lookup.lookup(var) {
+                        Some(value) => {
+                            let value: Arc<str> = Arc::from(value);
+                            shared.insert(var, value.clone());
+                            Fragment::Constant(Text::Shared(value))
+                        }
+                        None => fragment.clone(),
+                    },
+                },
+                _ => fragment.clone(),
+            })
+            .collect();
+        self.derive(fragments)
+    }
+
+    fn partial_filtered<F>(&self, lookup: &dyn Lookup, substitute: F) -> Template
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut fragments = Vec::new();
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Variable(ref var) if !substitute(var) => fragments.push(fragment.clone()),
+                Fragment::Variable(ref var) => match lookup.lookup(var) {
+                    Some(value) => fragments.push(Fragment::Constant(value.to_owned().into())),
+                    _ => fragments.push(fragment.clone()),
+                },
+                _ => fragments.push(fragment.clone()),
+            }
+        }
+        self.derive(fragments)
+    }
+
+    /// Renders the template, leniently: variables that the lookup cannot resolve render as the
+    /// empty string. Use `try_render` to treat them as errors instead.
+    pub fn render(&self, lookup: &dyn Lookup) -> String {
+        if !self.has_variables {
+            return self.constant_text();
+        }
+
+        let mut result = String::with_capacity(self.size_hint);
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => result.push_str(text),
+                Fragment::Variable(var) => if let Some(text) = lookup.lookup(var) {
+                    result.push_str(text);
+                },
+            }
+        }
+        result
+    }
+
+    /// Returns the spec of the template like `as_spec`, but computes it only once and caches it
+    /// until the template is modified.
+    pub fn spec(&self) -> &str {
+        self.spec.get_or_init(|| self.as_spec())
+    }
+
+    pub fn as_spec(&self) -> String {
+        let mut spec = String::new();
+        self.write_spec(&mut spec);
+        spec
+    }
+
+    /// Appends the spec of the template, as returned by `as_spec`, to `buf`.
+    pub fn write_spec(&self, buf: &mut String) {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => {
+                    for (index, part) in text.split('$').enumerate() {
+                        if index > 0 {
+                            buf.push_str("$$");
+                        }
+                        buf.push_str(part);
+                    }
+                }
+                Fragment::Variable(var) => {
+                    buf.push('$');
+                    for c in var.chars() {
+                        if is_escaped(c) {
+                            buf.push('\\');
+                        }
+                        buf.push(c);
+                    }
+                    buf.push('$');
+                }
+            }
+        }
+    }
+
+    /// Returns the concatenation of all constant text in the template, skipping variables.
+    pub fn constant_text(&self) -> String {
+        let mut text = String::new();
+        for fragment in &self.fragments {
+            if let Fragment::Constant(constant) = fragment {
+                text.push_str(constant);
+            }
+        }
+        text
+    }
+
+    /// Returns true if the template contains at least one variable.
+    pub fn has_variables(&self) -> bool {
+        self.has_variables
+    }
+
+    /// Returns the total length in bytes of the constant text, which is the minimum length of
+    /// any rendering. It is computed once when the template is created.
+    pub fn size_hint(&self) -> usize {
+        self.size_hint
+    }
+
+    /// Returns the number of times the variable `name` appears in the template.
+    pub fn count_occurrences(&self, name: &str) -> usize {
+        self.fragments
+            .iter()
+            .filter(|fragment| match fragment {
+                Fragment::Variable(var) => **var == *name,
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Renders the template, copying constants to the output and letting `resolve` write the
+    /// output of each variable.
+    fn render_with<F>(&self, mut resolve: F) -> String
+    where
+        F: FnMut(&str, &mut String),
+    {
+        let result: Result<String, ()> = self.try_render_with(|var, result| {
+            resolve(var, result);
+            Ok(())
+        });
+        result.unwrap_or_default()
+    }
+
+    /// Like `render_with`, but stops at the first error returned by `resolve`.
+    fn try_render_with<E, F>(&self, mut resolve: F) -> Result<String, E>
+    where
+        F: FnMut(&str, &mut String) -> Result<(), E>,
+    {
+        let mut result = String::with_capacity(self.size_hint);
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => result.push_str(text),
+                Fragment::Variable(var) => resolve(var, &mut result)?,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Renders the template, formatting variables written as `$name:width$` to exactly `width`
+    /// columns: shorter values are padded with spaces and longer values are truncated. A positive
+    /// width right-aligns the value and a negative width left-aligns it. Like the other `:` specs,
+    /// a spec that is not a number is ignored and the value of `name` written as it is.
+    pub fn render_aligned(&self, lookup: &dyn Lookup) -> String {
+        self.render_with(|var, result| {
+            let (name, spec) = split_spec(var);
+            match spec.and_then(|spec| spec.parse::<isize>().ok()) {
+                Some(width) => {
+                    let value = lookup.lookup(name).unwrap_or("");
+                    let columns = width.unsigned_abs();
+                    let end = value.char_indices().nth(columns).map_or(value.len(), |(i, _)| i);
+                    let value = &value[..end];
+                    let padding = std::iter::repeat_n(' ', columns - value.chars().count());
+                    if width < 0 {
+                        result.push_str(value);
+                        result.extend(padding);
+                    } else {
+                        result.extend(padding);
+                        result.push_str(value);
+                    }
+                }
+                None => if let Some(text) = lookup.lookup(name) {
+                    result.push_str(text);
+                },
+            }
+        })
+    }
+
+    /// Groups the template into chunks where each literal chunk holds all consecutive constant
+    /// text, so that writers can emit it in a single call. Each variable is its own chunk.
+    pub fn chunks(&self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => match chunks.last_mut() {
+                    Some(Chunk::Literal(literal)) => literal.push_str(text),
+                    _ => chunks.push(Chunk::Literal(text.to_string())),
+                },
+                Fragment::Variable(var) => chunks.push(Chunk::Variable(var.to_string())),
+            }
+        }
+        chunks
+    }
+
+    /// Returns the template in canonical form, with adjacent constants merged and empty constants
+    /// removed. Two templates render identically for all lookups exactly when their normalized
+    /// forms are equal.
+    pub fn normalize(&self) -> Template {
+        let mut fragments: Vec<Fragment> = Vec::with_capacity(self.fragments.len());
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) if text.is_empty() => (),
+                Fragment::Constant(text) => match fragments.last_mut() {
+                    Some(Fragment::Constant(last)) => last.to_mut().push_str(text),
+                    _ => fragments.push(fragment.clone()),
+                },
+                _ => fragments.push(fragment.clone()),
+            }
+        }
+        self.derive(fragments)
+    }
+
+    /// Renders the template like `render`, calling `observe` with the name and resolved value of
+    /// every variable in order. The output is not affected by the observer.
+    pub fn render_observing<F>(&self, lookup: &dyn Lookup, mut observe: F) -> String
+    where
+        F: FnMut(&str, Option<&str>),
+    {
+        self.render_with(|var, result| {
+            let value = lookup.lookup(var);
+            observe(var, value);
+            if let Some(text) = value {
+                result.push_str(text);
+            }
+        })
+    }
+
+    /// Converts the template into a list of `(is_variable, text)` tokens. Unlike the spec, the
+    /// tokens need no escaping.
+    pub fn to_tokens(&self) -> Vec<(bool, String)> {
+        self.fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Constant(text) => (false, text.to_string()),
+                Fragment::Variable(var) => (true, var.to_string()),
+            })
+            .collect()
+    }
+
+    /// Builds a template from tokens as returned by `to_tokens`. Variables with an empty name,
+    /// which no spec can express, are left out.
+    pub fn from_tokens(tokens: Vec<(bool, String)>) -> Template {
+        let mut pool = NamePool::new();
+        let fragments = tokens
+            .into_iter()
+            .filter_map(|(is_variable, text)| match is_variable {
+                true if text.is_empty() => None,
+                true => Some(Fragment::Variable(pool.intern(&text))),
+                false => Some(Fragment::Constant(text.into())),
+            })
+            .collect();
+        Template::with_fragments(fragments)
+    }
+
+    /// Consumes the template and returns its fragments, in order.
+    pub fn into_fragments(self) -> Vec<PublicFragment> {
+        self.fragments
+            .into_iter()
+            .map(|fragment| match fragment {
+                Fragment::Constant(text) => PublicFragment::Constant(text.into_string()),
+                Fragment::Variable(var) => PublicFragment::Variable(var.to_string()),
+            })
+            .collect()
+    }
+
+    /// Builds a template from fragments as returned by `into_fragments`. Variables with an empty
+    /// name, which no spec can express, are left out.
+    pub fn from_fragments(fragments: Vec<PublicFragment>) -> Template {
+        let mut pool = NamePool::new();
+        let fragments = fragments
+            .into_iter()
+            .filter_map(|fragment| match fragment {
+                PublicFragment::Constant(text) => Some(Fragment::Constant(text.into())),
+                PublicFragment::Variable(var) if var.is_empty() => None,
+                PublicFragment::Variable(var) => Some(Fragment::Variable(pool.intern(&var))),
+            })
+            .collect();
+        Template::with_fragments(fragments)
+    }
+
+    /// Renders the template like `render`, but fails with `RenderError::BudgetExceeded` if more
+    /// than `max_substitutions` variables would have to be resolved.
+    pub fn render_budgeted(
+        &self,
+        lookup: &dyn Lookup,
+        max_substitutions: usize,
+    ) -> Result<String, RenderError> {
+        let mut substitutions = 0;
+        self.try_render_with(|var, result| {
+            substitutions += 1;
+            if substitutions > max_substitutions {
+                return Err(RenderError::BudgetExceeded);
+            }
+            if let Some(text) = lookup.lookup(var) {
+                result.push_str(text);
+            }
+            Ok(())
+        })
+    }
+
+    /// Renders the template against a lookup producing owned values.
+    pub fn render_owned(&self, lookup: &dyn OwnedLookup) -> String {
+        self.render_with(|var, result| {
+            if let Some(text) = lookup.lookup_owned(var) {
+                result.push_str(&text);
+            }
+        })
+    }
+
+    /// Calls `f` with mutable access to each variable name, in order, so that variables can be
+    /// renamed in place. A variable whose name `f` leaves empty is removed, and the constants
+    /// around it merged.
+    pub fn for_each_variable_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut String),
+    {
+        let mut removed = false;
+        for fragment in &mut self.fragments {
+            if let Fragment::Variable(var) = fragment {
+                let mut name = var.to_string();
+                f(&mut name);
+                removed |= name.is_empty();
+                if **var != *name {
+                    let renamed: Arc<str> = Arc::from(name);
+                    if let Some(default) = self.defaults.remove(&*var) {
+                        self.defaults.insert(renamed.clone(), default);
+                    }
+                    *var = renamed;
+                }
+            }
+        }
+        if removed {
+            self.fragments
+                .retain(|fragment| !matches!(fragment, Fragment::Variable(var) if var.is_empty()));
+            *self = self.normalize();
+        }
+        self.spec = OnceLock::new();
+    }
+
+    /// Renders the template once per lookup and joins the results with `sep`.
+    pub fn render_join(&self, lookups: &[&dyn Lookup], sep: &str) -> String {
+        let mut result = String::new();
+        for (index, lookup) in lookups.iter().enumerate() {
+            if index > 0 {
+                result.push_str(sep);
+            }
+            result.push_str(&self.render(*lookup));
+        }
+        result
+    }
+
+    /// Compares two templates ignoring how their constant text is split into fragments.
+    pub fn structurally_eq(&self, other: &Template) -> bool {
+        self.normalize() == other.normalize()
+    }
+
+    /// Renders the template once per lookup, in order. A single buffer is reused across the
+    /// renders, so each output is allocated exactly once at its final size.
+    pub fn render_all(&self, lookups: &[&dyn Lookup]) -> Vec<String> {
+        let mut buffer = String::with_capacity(self.size_hint);
+        lookups
+            .iter()
+            .map(|lookup| {
+                buffer.clear();
+                self.render_to(*lookup, &mut buffer);
+                buffer.as_str().to_owned()
+            })
+            .collect()
+    }
+
+    /// Appends the rendering of the template to `buffer`.
+    fn render_to(&self, lookup: &dyn Lookup, buffer: &mut String) {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => buffer.push_str(text),
+                Fragment::Variable(var) => if let Some(text) = lookup.lookup(var) {
+                    buffer.push_str(text);
+                },
+            }
+        }
+    }
+
+    /// Renders the template, passing each resolved value through `escaper`. Constants are
+    /// written as they are.
+    pub fn render_escaped(&self, lookup: &dyn Lookup, escaper: &dyn OutputEscaper) -> String {
+        self.render_with(|var, result| {
+            if let Some(text) = lookup.lookup(var) {
+                result.push_str(&escaper.escape(text));
+            }
+        })
+    }
+
+    /// Returns a template holding only the variables of this one, in order, with all constant
+    /// text removed. Useful for reporting what is left after a `partial`.
+    pub fn unresolved(&self) -> Template {
+        let fragments = self
+            .fragments
+            .iter()
+            .filter(|fragment| matches!(fragment, Fragment::Variable(_)))
+            .cloned()
+            .collect();
+        self.derive(fragments)
+    }
+
+    /// Renders the template, applying filters to variables written as `$name|filter$`. Filters are
+    /// looked up by name in `filters` and applied from left to right, so `$name|a|b$` applies `a`
+    /// and then `b`. Naming an unregistered filter fails with `RenderError::UnknownFilter`.
+    pub fn render_with_filters(
+        &self,
+        lookup: &dyn Lookup,
+        filters: &HashMap<String, Filter>,
+    ) -> Result<String, RenderError> {
+        self.try_render_with(|var, result| {
+            let mut parts = var.split('|');
+            let name = parts.next().unwrap_or_default();
+            let mut value = lookup.lookup(name).map(|value| value.to_string());
+            for filter_name in parts {
+                let filter = filters
+                    .get(filter_name)
+                    .ok_or_else(|| RenderError::UnknownFilter(filter_name.to_string()))?;
+                value = value.map(|value| filter(&value));
+            }
+            if let Some(text) = value {
+                result.push_str(&text);
+            }
+            Ok(())
+        })
+    }
+
+    /// Appends constant text to the template, merging it into a preceding constant.
+    pub fn push_constant(&mut self, text: &str) -> &mut Template {
+        if !text.is_empty() {
+            match self.fragments.last_mut() {
+                Some(Fragment::Constant(last)) => last.to_mut().push_str(text),
+                _ => self.fragments.push(Fragment::Constant(text.to_string().into())),
+            }
+            self.size_hint += text.len();
+            self.spec = OnceLock::new();
+        }
+        self
+    }
+
+    /// Appends a variable to the template. An empty name, which no spec can express, appends
+    /// nothing.
+    pub fn push_variable(&mut self, name: &str) -> &mut Template {
+        if name.is_empty() {
+            return self;
+        }
+        self.fragments.push(Fragment::Variable(Arc::from(name)));
+        self.has_variables = true;
+        self.spec = OnceLock::new();
+        self
+    }
+
+    /// Renders the template strictly, failing with `RenderError::MissingVariable` for the first
+    /// variable that the lookup cannot resolve.
+    pub fn try_render(&self, lookup: &dyn Lookup) -> Result<String, RenderError> {
+        self.try_render_with(|var, result| match lookup.lookup(var) {
+            Some(text) => {
+                result.push_str(text);
+                Ok(())
+            }
+            None => Err(RenderError::MissingVariable(var.to_string())),
+        })
+    }
+
+    /// Renders the template, resolving variables written as `$name:default$` to `default` when
+    /// the lookup cannot resolve `name`. The default is literal text, unless the template was
+    /// parsed with `ParseOptions::nested_defaults`: the default is then a template of its own,
+    /// rendered against the same lookup, so it may refer to other variables. A name written as `a??b` falls back to the variable `b`
+    /// when `a` does not resolve, before any default is used.
+    pub fn render_with_defaults(&self, lookup: &dyn Lookup) -> String {
+        self.render_with(|var, result| {
+            let (names, default) = split_spec(var);
+            let value = names.split("??").find_map(|name| lookup.lookup(name));
+            match (value, default) {
+                (Some(text), _) => result.push_str(text),
+                (None, Some(default)) => match self.defaults.get(var) {
+                    Some(template) => result.push_str(&template.render_with_defaults(lookup)),
+                    None => result.push_str(default),
+                },
+                (None, None) => (),
+            }
+        })
+    }
+
+    /// Renders the template by calling `f` for each variable with its name and the zero-based
+    /// index of the variable among all variables of the template. Variables for which `f`
+    /// returns `None` render as the empty string.
+    pub fn render_indexed<F>(&self, mut f: F) -> String
+    where
+        F: FnMut(&str, usize) -> Option<String>,
+    {
+        let mut index = 0;
+        self.render_with(|var, result| {
+            if let Some(text) = f(var, index) {
+                result.push_str(&text);
+            }
+            index += 1;
+        })
+    }
+
+    /// Returns true if the first fragment of the template is a variable.
+    pub fn starts_with_variable(&self) -> bool {
+        matches!(self.fragments.first(), Some(Fragment::Variable(_)))
+    }
+
+    /// Returns true if the last fragment of the template is a variable.
+    pub fn ends_with_variable(&self) -> bool {
+        matches!(self.fragments.last(), Some(Fragment::Variable(_)))
+    }
+
+    /// Renders the template against anything that converts into a lookup.
+    pub fn render_into<T: IntoLookup>(&self, input: T) -> String {
+        self.render_owned(&input.into_lookup())
+    }
+
+    /// Renders the template into `buffer`, replacing its previous contents and keeping its
+    /// allocation, and returns the rendered text.
+    pub fn render_reusing<'b>(
+        &self,
+        buffer: &'b mut RenderBuffer,
+        lookup: &dyn Lookup,
+    ) -> &'b str {
+        buffer.buffer.clear();
+        buffer.buffer.reserve(self.size_hint);
+        self.render_to(lookup, &mut buffer.buffer);
+        &buffer.buffer
+    }
+
+    /// Walks the fragments of the template in order, handing each to `visitor`.
+    pub fn accept<V: Visitor>(&self, visitor: &mut V) {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => visitor.visit_constant(text),
+                Fragment::Variable(var) => visitor.visit_variable(var),
+            }
+        }
+    }
+
+    /// Renders the template and compares the output with `expected`, reporting the first
+    /// difference, if any, for golden-file tests.
+    pub fn assert_render(&self, lookup: &dyn Lookup, expected: &str) -> Result<(), RenderMismatch> {
+        let actual = self.render(lookup);
+        if actual == expected {
+            return Ok(());
+        }
+        let offset = actual
+            .bytes()
+            .zip(expected.bytes())
+            .position(|(a, e)| a != e)
+            .unwrap_or_else(|| actual.len().min(expected.len()));
+        Err(RenderMismatch {
+            offset,
+            expected: context_window(expected, offset),
+            actual: context_window(&actual, offset),
+        })
+    }
+
+    /// Returns a wrapper that renders the template against `lookup` when displayed, for use in
+    /// `write!` and `format!` without an intermediate string.
+    pub fn display_with<'a>(&'a self, lookup: &'a dyn Lookup) -> Rendered<'a> {
+        Rendered {
+            template: self,
+            lookup,
+        }
+    }
+
+    /// Parses a template like `new`, but takes a `$` that cannot start a name literally, see
+    /// `ParseOptions::smart_dollars`.
+    pub fn new_smart(spec: &str) -> Result<Template, ParseError> {
+        Template::with_options(spec, &ParseOptions::new().smart_dollars(true))
+    }
+
+    /// Renders the template against a lookup producing borrowed or owned values.
+    pub fn render_cow(&self, lookup: &dyn CowLookup) -> String {
+        self.render_with(|var, result| {
+            if let Some(text) = lookup.lookup_cow(var) {
+                result.push_str(&text);
+            }
+        })
+    }
+
+    /// Returns a copy of the template with every run of whitespace in its constants collapsed to
+    /// a single space. Variables and the values they render are left untouched.
+    pub fn minify_whitespace(&self) -> Template {
+        let fragments = self
+            .fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Constant(text) => {
+                    let mut minified = String::with_capacity(text.len());
+                    let mut in_whitespace = false;
+                    for c in text.chars() {
+                        if !c.is_whitespace() {
+                            minified.push(c);
+                        } else if !in_whitespace {
+                            minified.push(' ');
+                        }
+                        in_whitespace = c.is_whitespace();
+                    }
+                    Fragment::Constant(minified.into())
+                }
+                _ => fragment.clone(),
+            })
+            .collect();
+        self.derive(fragments)
+    }
+
+    /// Consumes the template and substitutes every variable `lookup` resolves, returning the
+    /// result in normalized form, with the substituted values merged into the constants around
+    /// them.
+    pub fn bake(self, lookup: &dyn Lookup) -> Template {
+        self.partial(lookup).normalize()
+    }
+
+    /// Parses a template like `new`, but fails if any variable appears more than once.
+    pub fn new_unique(spec: &str) -> Result<Template, ParseError> {
+        Template::with_options(spec, &ParseOptions::new().unique_variables(true))
+    }
+
+    /// Renders the template, writing `marker` in place of each variable the lookup cannot
+    /// resolve, so that missing values stand out in previews.
+    pub fn render_lossy(&self, lookup: &dyn Lookup, marker: &str) -> String {
+        self.render_with(|var, result| result.push_str(lookup.lookup(var).unwrap_or(marker)))
+    }
+
+    /// Renders the template like `render`, but catches a panic in the lookup and fails with
+    /// `RenderError::LookupPanicked` naming the variable being resolved.
+    pub fn render_catch(
+        &self,
+        lookup: &(dyn Lookup + RefUnwindSafe),
+    ) -> Result<String, RenderError> {
+        self.try_render_with(|var, result| {
+            match panic::catch_unwind(|| lookup.lookup(var)) {
+                Ok(value) => result.push_str(value.unwrap_or("")),
+                Err(_) => return Err(RenderError::LookupPanicked(var.to_string())),
+            }
+            Ok(())
+        })
+    }
+
+    /// Renders the template, replacing each variable the lookup cannot resolve with `fallback`
+    /// rendered against the same lookup, where `$var$` names the missing variable.
+    pub fn render_with_fallback(&self, lookup: &dyn Lookup, fallback: &Template) -> String {
+        self.render_with(|var, result| match lookup.lookup(var) {
+            Some(text) => result.push_str(text),
+            None => {
+                let missing = SingleLookup::new("var", var);
+                let mut chain = ChainedLookup::new();
+                chain.add(&missing);
+                chain.add(lookup);
+                fallback.render_to(&chain, result);
+            }
+        })
+    }
+
+    /// Builds a regex matching exactly the strings this template can render, with a named
+    /// capture group per variable, which may match the empty string just as a variable may render
+    /// as one. Earlier variables match as little text as possible. A variable that repeats, or
+    /// whose name is not a valid group name, fails to compile.
+    #[cfg(feature = "regex")]
+    pub fn to_regex(&self) -> Result<regex::Regex, regex::Error> {
+        let mut pattern = String::from("(?s)^");
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => pattern.push_str(&regex::escape(text)),
+                Fragment::Variable(var) => {
+                    pattern.push_str("(?P<");
+                    pattern.push_str(var);
+                    pattern.push_str(">.*?)");
+                }
+            }
+        }
+        pattern.push('$');
+        regex::Regex::new(&pattern)
+    }
+
+    /// Matches `input` against the template and returns the text each variable stands for, or
+    /// `None` if the template cannot render `input`. See `to_regex` for how text is split
+    /// between variables.
+    #[cfg(feature = "regex")]
+    pub fn extract(&self, input: &str) -> Option<HashMap<String, String>> {
+        let regex = self.to_regex().ok()?;
+        let captures = regex.captures(input)?;
+        let values = regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+            .collect();
+        Some(values)
+    }
+
+    /// Renders the template, replacing each variable the lookup cannot resolve with the text
+    /// `on_missing` returns for its name.
+    pub fn render_or_else<F>(&self, lookup: &dyn Lookup, mut on_missing: F) -> String
+    where
+        F: FnMut(&str) -> String,
+    {
+        self.render_with(|var, result| match lookup.lookup(var) {
+            Some(text) => result.push_str(text),
+            None => result.push_str(&on_missing(var)),
+        })
+    }
+
+    /// Parses a template spec given as UTF-16, as used across some FFI boundaries.
+    pub fn from_utf16(data: &[u16]) -> Result<Template, TemplateError> {
+        let spec = String::from_utf16(data)?;
+        Ok(Template::new(&spec)?)
+    }
+
+    /// Renders the template like `render`, returning the output as UTF-16.
+    pub fn render_utf16(&self, lookup: &dyn Lookup) -> Vec<u16> {
+        self.render(lookup).encode_utf16().collect()
+    }
+
+    /// Splits the template at each occurrence of the variable `name`, returning the pieces
+    /// between them. A template without the variable is returned whole.
+    pub fn split_at_variable(&self, name: &str) -> Vec<Template> {
+        self.fragments
+            .split(|fragment| matches!(fragment, Fragment::Variable(var) if **var == *name))
+            .map(|fragments| self.derive(fragments.to_vec()))
+            .collect()
+    }
+
+    /// Renders the template lazily, yielding the output one fragment at a time so that it can be
+    /// written out without building the whole string. Unresolved variables yield nothing.
+    pub fn render_chunks<'a>(&'a self, lookup: &'a dyn Lookup) -> impl Iterator<Item = &'a str> {
+        self.fragments.iter().filter_map(move |fragment| match fragment {
+            Fragment::Constant(text) => Some(&**text),
+            Fragment::Variable(var) => lookup.lookup(var),
+        })
+    }
+
+    /// Renders the template like `render`, but fails with `RenderError::OutputTooLarge` as soon
+    /// as the output would exceed `max_total_bytes`.
+    pub fn render_capped(
+        &self,
+        lookup: &dyn Lookup,
+        max_total_bytes: usize,
+    ) -> Result<String, RenderError> {
+        let mut result = String::with_capacity(self.size_hint.min(max_total_bytes));
+        for fragment in &self.fragments {
+            let text = match fragment {
+                Fragment::Constant(text) => &**text,
+                Fragment::Variable(var) => lookup.lookup(var).unwrap_or(""),
+            };
+            if result.len() + text.len() > max_total_bytes {
+                return Err(RenderError::OutputTooLarge);
+            }
+            result.push_str(text);
+        }
+        Ok(result)
+    }
+
+    /// Fills the named blocks of a base template, replacing each variable written as
+    /// `$block:name$` with the fragments of the template stored under `name` in `blocks`.
+    /// Blocks without an entry are left in place.
+    pub fn with_blocks(&self, blocks: &HashMap<String, Template>) -> Template {
+        let mut fragments = Vec::with_capacity(self.fragments.len());
+        let mut filled = Vec::new();
+        for fragment in &self.fragments {
+            let block = match fragment {
+                Fragment::Variable(var) => var
+                    .strip_prefix("block:")
+                    .and_then(|name| blocks.get(name)),
+                _ => None,
+            };
+            match block {
+                Some(block) => {
+                    fragments.extend(block.fragments.iter().cloned());
+                    filled.push(block);
+                }
+                None => fragments.push(fragment.clone()),
+            }
+        }
+        let mut template = self.derive(fragments);
+        for block in filled {
+            template.defaults.extend(block.defaults.clone());
+        }
+        template
+    }
+
+    /// Renders the template like `render`, also returning how long the lookup took to resolve
+    /// each variable, in order, to help find slow lookups.
+    #[cfg(feature = "std")]
+    pub fn render_timed(&self, lookup: &dyn Lookup) -> (String, Vec<(String, Duration)>) {
+        let mut timings = Vec::new();
+        let rendered = self.render_with(|var, result| {
+            let start = Instant::now();
+            let value = lookup.lookup(var);
+            timings.push((var.to_string(), start.elapsed()));
+            if let Some(text) = value {
+                result.push_str(text);
+            }
+        });
+        (rendered, timings)
+    }
+
+    /// Renders the template only if the lookup resolves every variable, like `try_render` but
+    /// without naming the missing variable.
+    pub fn render_complete(&self, lookup: &dyn Lookup) -> Option<String> {
+        self.try_render(lookup).ok()
+    }
+
+    /// Renders the template against serializable data, resolving variables as dotted paths into
+    /// it, see `JsonLookup`.
+    #[cfg(feature = "json")]
+    pub fn render_serialize<T: serde::Serialize>(
+        &self,
+        data: &T,
+    ) -> Result<String, serde_json::Error> {
+        Ok(self.render_owned(&JsonLookup::from_serialize(data)?))
+    }
+
+    /// Renders the template like `render`, also counting the bytes of output produced by
+    /// constants and by variables.
+    pub fn render_stats(&self, lookup: &dyn Lookup) -> RenderStats {
+        let mut output = String::with_capacity(self.size_hint);
+        let mut variable_bytes = 0;
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => output.push_str(text),
+                Fragment::Variable(var) => if let Some(text) = lookup.lookup(var) {
+                    output.push_str(text);
+                    variable_bytes += text.len();
+                },
+            }
+        }
+        RenderStats {
+            constant_bytes: output.len() - variable_bytes,
+            output,
+            variable_bytes,
+        }
+    }
+
+    /// Renders the template with every value HTML-escaped, except for variables written as
+    /// `$name!raw$`, whose values are written verbatim.
+    pub fn render_html(&self, lookup: &dyn Lookup) -> String {
+        self.render_transformed(lookup, |text| HtmlEscaper.escape(text))
+    }
+
+    /// Renders the template, passing every resolved value through `transform` except for
+    /// variables written as `$name!raw$`, whose values are written verbatim.
+    pub fn render_transformed<F>(&self, lookup: &dyn Lookup, transform: F) -> String
+    where
+        F: Fn(&str) -> String,
+    {
+        self.render_with(|var, result| match var.strip_suffix("!raw") {
+            Some(name) => if let Some(text) = lookup.lookup(name) {
+                result.push_str(text);
+            },
+            None => if let Some(text) = lookup.lookup(var) {
+                result.push_str(&transform(text));
+            },
+        })
+    }
+
+    /// Renders the template, rendering the template `expand` returns for each variable the
+    /// lookup cannot resolve, recursively and against the same lookup, or nothing if it returns
+    /// `None`. The expander must not expand a variable into a template that contains it again.
+    pub fn render_expand<F>(&self, lookup: &dyn Lookup, mut expand: F) -> String
+    where
+        F: FnMut(&str) -> Option<Template>,
+    {
+        let mut result = String::with_capacity(self.size_hint);
+        self.expand_to(lookup, &mut expand, &mut result);
+        result
+    }
+
+    fn expand_to(
+        &self,
+        lookup: &dyn Lookup,
+        expand: &mut dyn FnMut(&str) -> Option<Template>,
+        result: &mut String,
+    ) {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => result.push_str(text),
+                Fragment::Variable(var) => match lookup.lookup(var) {
+                    Some(text) => result.push_str(text),
+                    None => if let Some(template) = expand(var) {
+                        template.expand_to(lookup, expand, result);
+                    },
+                },
+            }
+        }
+    }
+
+    /// Splits the template into its constant texts and its variable names, in order, along with
+    /// the fragment order: for each fragment, whether it is a variable and its index into the
+    /// respective list.
+    pub fn decompose(&self) -> (Vec<String>, Vec<String>, Vec<(bool, usize)>) {
+        let mut constants = Vec::new();
+        let mut variables = Vec::new();
+        let mut order = Vec::with_capacity(self.fragments.len());
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => {
+                    order.push((false, constants.len()));
+                    constants.push(text.to_string());
+                }
+                Fragment::Variable(var) => {
+                    order.push((true, variables.len()));
+                    variables.push(var.to_string());
+                }
+            }
+        }
+        (constants, variables, order)
+    }
+
+    /// Renders the template like `render`, but resolves all distinct variables concurrently, on
+    /// up to 16 threads, before assembling the output. Meant for lookups that are slow to answer,
+    /// such as remote services. A panic in the lookup is propagated to the caller.
+    #[cfg(feature = "std")]
+    pub fn render_parallel(&self, lookup: &(dyn Lookup + Sync)) -> String {
+        let mut names: Vec<String> = Vec::new();
+        for fragment in &self.fragments {
+            if let Fragment::Variable(var) = fragment {
+                if !names.iter().any(|name| **name == **var) {
+                    names.push(var.to_string());
+                }
+            }
+        }
+        let chunk_size = names.len().div_ceil(MAX_PARALLEL_LOOKUPS).max(1);
+        let values: HashMap<&str, &str> = std::thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|name| Some((name.as_str(), lookup.lookup(name)?)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            let mut values = HashMap::new();
+            for handle in handles {
+                match handle.join() {
+                    Ok(resolved) => values.extend(resolved),
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+            values
+        });
+        self.render(&values)
+    }
+
+    /// Renders the template against a lookup that needs mutable access while resolving. The
+    /// lookup is asked once per occurrence, in order.
+    pub fn render_mut(&self, lookup: &mut dyn MutLookup) -> String {
+        self.render_with(|var, result| {
+            if let Some(text) = lookup.lookup_mut(var) {
+                result.push_str(&text);
+            }
+        })
+    }
+
+    /// Compares the values the variables resolve to under `old_lookup` and `new_lookup`, returning
+    /// the index of each variable fragment whose value changed, with its new value. An unresolved
+    /// variable counts as the empty string.
+    pub fn render_delta(
+        &self,
+        old_lookup: &dyn Lookup,
+        new_lookup: &dyn Lookup,
+    ) -> Vec<(usize, String)> {
+        let mut delta = Vec::new();
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            if let Fragment::Variable(var) = fragment {
+                let old = old_lookup.lookup(var).unwrap_or("");
+                let new = new_lookup.lookup(var).unwrap_or("");
+                if old != new {
+                    delta.push((index, new.to_string()));
+                }
+            }
+        }
+        delta
+    }
+
+    /// Renders the template against `base` with the `(key, value)` pairs in `overrides` taking
+    /// precedence over it.
+    pub fn render_over(&self, base: &dyn Lookup, overrides: &[(&str, &str)]) -> String {
+        let mut chain = ChainedLookup::new();
+        chain.add(&overrides);
+        chain.add(base);
+        self.render(&chain)
+    }
+
+    /// Renders the template into tokens that keep constant text and variable values apart, for
+    /// targets that present them differently.
+    pub fn render_tokens(&self, lookup: &dyn Lookup) -> Vec<Token> {
+        self.fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::Constant(text) => Token::Literal(text.to_string()),
+                Fragment::Variable(var) => Token::Value {
+                    name: var.to_string(),
+                    value: lookup.lookup(var).map(|value| value.to_string()),
+                },
+            })
+            .collect()
+    }
+
+    /// Renders the template, formatting the values of variables written as `$name:pattern$` as
+    /// numbers, see `format_number` for the patterns: `$amount:#,###$` renders `1234567` as
+    /// `1,234,567`. Values that are not numbers, and unknown patterns, render the raw value.
+    #[cfg(feature = "num-format")]
+    pub fn render_numbers(&self, lookup: &dyn Lookup) -> String {
+        self.render_with(|var, result| {
+            let (name, pattern) = split_spec(var);
+            if let Some(text) = lookup.lookup(name) {
+                match pattern.and_then(|pattern| format_number(text, pattern)) {
+                    Some(formatted) => result.push_str(&formatted),
+                    None => result.push_str(text),
+                }
+            }
+        })
+    }
+
+    /// Returns a Rust expression that builds this template, for generated code: the spec as a
+    /// string literal passed to `stt::Template::new`.
+    pub fn to_rust_source(&self) -> String {
+        format!("stt::Template::new({:?}).unwrap()", self.as_spec())
+    }
+
+    /// Renders the template chunk by chunk into `sink`, stopping as soon as the sink returns
+    /// `Break`; the remaining fragments are not looked up. Returns `Break` if rendering stopped
+    /// early.
+    pub fn render_with_sink<F>(&self, lookup: &dyn Lookup, mut sink: F) -> ControlFlow<()>
+    where
+        F: FnMut(&str) -> ControlFlow<()>,
+    {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => sink(text)?,
+                Fragment::Variable(var) => if let Some(text) = lookup.lookup(var) {
+                    sink(text)?;
+                },
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Renders the template, writing each variable the lookup cannot resolve as `$$name$$`, so
+    /// that stages in between that treat the output as plain text leave it alone. See
+    /// `from_deferred` for turning the output back into a template for a later stage.
+    pub fn render_deferred(&self, lookup: &dyn Lookup) -> String {
+        self.render_with(|var, result| match lookup.lookup(var) {
+            Some(text) => result.push_str(text),
+            None => {
+                result.push_str("$$");
+                result.push_str(var);
+                result.push_str("$$");
+            }
+        })
+    }
+
+    /// Builds a template from the output of `render_deferred`, turning every `$$name$$` back
+    /// into a variable and taking all other text literally. A name may not be empty nor contain
+    /// `$` or whitespace.
+    pub fn from_deferred(text: &str) -> Template {
+        let mut template = Template::empty();
+        let mut rest = text;
+        while let Some(start) = rest.find("$$") {
+            let after = &rest[start + 2..];
+            let name = after.find("$$").map(|end| &after[..end]).filter(|name| {
+                !name.is_empty() && !name.contains(|c: char| c == '$' || c.is_whitespace())
+            });
+            match name {
+                Some(name) => {
+                    template.push_constant(&rest[..start]);
+                    template.push_variable(name);
+                    rest = &after[name.len() + 2..];
+                }
+                None => {
+                    template.push_constant(&rest[..start + 1]);
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        template.push_constant(rest);
+        template
+    }
+
+    /// Renders the template, parsing every resolved value as a template of its own and rendering
+    /// it in turn, against the same lookup. Values that do not parse are taken literally. Fails
+    /// when a variable expands to itself, or when expansion goes deeper than 64 levels.
+    pub fn render_recursive(&self, lookup: &dyn Lookup) -> Result<String, RenderError> {
+        let mut result = String::with_capacity(self.size_hint);
+        self.recurse_to(lookup, &mut Vec::new(), &mut result)?;
+        Ok(result)
+    }
+
+    fn recurse_to(
+        &self,
+        lookup: &dyn Lookup,
+        expanding: &mut Vec<String>,
+        result: &mut String,
+    ) -> Result<(), RenderError> {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => result.push_str(text),
+                Fragment::Variable(var) => {
+                    if let Some(start) = expanding.iter().position(|name| **name == **var) {
+                        let mut path = expanding[start..].to_vec();
+                        path.push(var.to_string());
+                        return Err(RenderError::Cycle(path));
+                    }
+                    if expanding.len() == MAX_RECURSION_DEPTH {
+                        return Err(RenderError::RecursionLimit);
+                    }
+                    if let Some(text) = lookup.lookup(var) {
+                        match Template::new(text) {
+                            Ok(template) => {
+                                expanding.push(var.to_string());
+                                template.recurse_to(lookup, expanding, result)?;
+                                expanding.pop();
+                            }
+                            Err(_) => result.push_str(text),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the template against `lookup`, falling back to `default` for the keys it cannot
+    /// resolve.
+    pub fn render_or(&self, lookup: &dyn Lookup, default: &dyn Lookup) -> String {
+        let mut chain = ChainedLookup::new();
+        chain.add(lookup);
+        chain.add(default);
+        self.render(&chain)
+    }
+
+    /// Renders the template, encoding the bytes of the values of variables written as
+    /// `$name:base64$` or `$name:hex$`, as far as the respective features are enabled. Values of
+    /// other variables are written as they are.
+    #[cfg(any(feature = "base64", feature = "hex"))]
+    pub fn render_encoded(&self, lookup: &dyn Lookup) -> String {
+        self.render_with(|var, result| {
+            let (name, encoding) = split_spec(var);
+            if let Some(text) = lookup.lookup(name) {
+                match encoding {
+                    #[cfg(feature = "base64")]
+                    Some("base64") => result.push_str(&base64::Engine::encode(
+                        &base64::engine::general_purpose::STANDARD,
+                        text,
+                    )),
+                    #[cfg(feature = "hex")]
+                    Some("hex") => result.push_str(&hex::encode(text)),
+                    _ => result.push_str(text),
+                }
+            }
+        })
+    }
+
+    /// Renders the template once per row of tabular data, resolving variables to the row's cells
+    /// by the column names in `header`, as with `CsvRowLookup`. The cells are read from `rows`
+    /// in place, without copying the header or the rows.
+    pub fn render_csv(&self, header: &[String], rows: &[Vec<String>]) -> Vec<String> {
+        rows.iter()
+            .map(|row| {
+                self.render_with(|var, result| {
+                    let column = header.iter().position(|name| name == var);
+                    if let Some(cell) = column.and_then(|column| row.get(column)) {
+                        result.push_str(cell);
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Renders the template into `out`, fragment by fragment, stopping at the first write that
+    /// fails and returning its error.
+    pub fn render_fmt<W: fmt::Write>(&self, lookup: &dyn Lookup, out: &mut W) -> fmt::Result {
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => out.write_str(text)?,
+                Fragment::Variable(var) => if let Some(text) = lookup.lookup(var) {
+                    out.write_str(text)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the template, writing variables written as `$count:item|items$` as the singular
+    /// form when the value of `count` is 1 and as the plural form otherwise, following English
+    /// rules. Values that are not whole numbers, and all other variables, are written as they are.
+    pub fn render_plural(&self, lookup: &dyn Lookup) -> String {
+        self.render_with(|var, result| {
+            let (name, forms) = split_spec(var);
+            let text = match lookup.lookup(name) {
+                Some(text) => text,
+                None => return,
+            };
+            let forms = forms.and_then(|forms| forms.split_once('|'));
+            match (forms, text.trim().parse::<i64>()) {
+                (Some((singular, _)), Ok(1)) => result.push_str(singular),
+                (Some((_, plural)), Ok(_)) => result.push_str(plural),
+                _ => result.push_str(text),
+            }
+        })
+    }
+
+    /// Renders the template with values escaped for use inside a JSON string literal, see
+    /// `JsonEscaper`. Constants are written as they are.
+    pub fn render_json_escaped(&self, lookup: &dyn Lookup) -> String {
+        self.render_escaped(lookup, &JsonEscaper)
+    }
+
+    /// Renders the template and maps the output back to the template: for each fragment, in
+    /// order, the byte range of the output it produced, which is empty for unresolved variables,
+    /// along with the fragment's index.
+    pub fn render_with_map(&self, lookup: &dyn Lookup) -> (String, Vec<(Range<usize>, usize)>) {
+        let mut result = String::with_capacity(self.size_hint);
+        let mut map = Vec::with_capacity(self.fragments.len());
+        for (index, fragment) in self.fragments.iter().enumerate() {
+            let start = result.len();
+            match fragment {
+                Fragment::Constant(text) => result.push_str(text),
+                Fragment::Variable(var) => if let Some(text) = lookup.lookup(var) {
+                    result.push_str(text);
+                },
+            }
+            map.push((start..result.len(), index));
+        }
+        (result, map)
+    }
+
+    /// Renders the template like `render`, unless more than `max` distinct variables cannot be
+    /// resolved, which suggests the lookup is the wrong one for the template.
+    pub fn render_max_missing(
+        &self,
+        lookup: &dyn Lookup,
+        max: usize,
+    ) -> Result<String, RenderError> {
+        let mut missing = HashSet::new();
+        let result = self.render_with(|var, result| match lookup.lookup(var) {
+            Some(text) => result.push_str(text),
+            None => {
+                missing.insert(var.to_string());
+            }
+        });
+        if missing.len() > max {
+            return Err(RenderError::TooManyMissing(missing.len()));
+        }
+        Ok(result)
+    }
+
+    /// Renders the template, leaving the writing of every fragment to `strategy`.
+    pub fn render_strategy(&self, lookup: &dyn Lookup, strategy: &dyn RenderStrategy) -> String {
+        let mut result = String::with_capacity(self.size_hint);
+        for fragment in &self.fragments {
+            match fragment {
+                Fragment::Constant(text) => strategy.write_constant(&mut result, text),
+                Fragment::Variable(var) => {
+                    strategy.write_variable(&mut result, var, lookup.lookup(var))
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn empty_template_yields_empty_string() {
+        let template = Template::new("").unwrap();
+        assert_eq!(template.render(&EmptyLookup::new()), "");
+    }
+
+    #[test]
+    fn variable_only_yields_value_of_variable() {
+        let template = Template::new("$foo$").unwrap();
+        assert_eq!(template.render(&SingleLookup::new("foo", "value")), "value");
+    }
+
+    #[test]
+    fn variable_wrapped_with_constants() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(
+            template.render(&SingleLookup::new("who", "world")),
+            "Hello world!"
+        );
+    }
+
+    #[test]
+    fn chained_lookup() {
+        let first = SingleLookup::new("alfa", "beta");
         let second = SingleLookup::new("who", "world");
         let mut lookup = ChainedLookup::new();
         lookup.add(&first);
-        lookup.add(&second);
+        lookup.add(&second);
+
+        let template = Template::new("Hello $who$!").unwrap();
+
+        assert_eq!(template.render(&lookup), "Hello world!");
+    }
+
+    #[test]
+    fn borrowed_map_lookup() {
+        let who = String::from("world");
+        let mut map = HashMap::new();
+        map.insert("who", who.as_str());
+        map.insert("greeting", "Hello");
+
+        let template = Template::new("$greeting$ $who$!").unwrap();
+
+        assert_eq!(template.render(&map), "Hello world!");
+    }
+
+    #[test]
+    fn count_occurrences_of_variable() {
+        let template = Template::new("$a$ and $b$, $a$ or $a$").unwrap();
+        assert_eq!(template.count_occurrences("missing"), 0);
+        assert_eq!(template.count_occurrences("b"), 1);
+        assert_eq!(template.count_occurrences("a"), 3);
+    }
+
+    #[test]
+    fn constant_only_template_renders_without_lookup() {
+        let template = Template::new("No $$ variables here").unwrap();
+        assert!(!template.has_variables());
+        assert_eq!(template.render(&EmptyLookup::new()), "No $ variables here");
+        assert_eq!(
+            template.render(&ConstantLookup::new(String::from("x"))),
+            "No $ variables here"
+        );
+    }
+
+    #[test]
+    fn has_variables_is_updated_by_partial() {
+        let template = Template::new("$a$ and $b$").unwrap();
+        assert!(template.has_variables());
+
+        let template = template.set("a", "1");
+        assert!(template.has_variables());
+
+        let template = template.set("b", "2");
+        assert!(!template.has_variables());
+        assert_eq!(template.render(&EmptyLookup::new()), "1 and 2");
+    }
+
+    #[test]
+    fn constant_text_skips_variables() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(template.constant_text(), "Hello !");
+
+        let template = Template::new("$who$").unwrap();
+        assert_eq!(template.constant_text(), "");
+    }
+
+    #[test]
+    fn templates_sort_by_structure() {
+        let mut templates: Vec<Template> = ["cherry", "banana $y$", "apple", "banana $x$"]
+            .iter()
+            .map(|spec| Template::new(spec).unwrap())
+            .collect();
+        let mut by_spec = templates.clone();
+
+        templates.sort();
+        by_spec.sort_by_key(|template| template.as_spec());
+
+        assert_eq!(templates, by_spec);
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn argv_lookup_reads_separate_values() {
+        let lookup = ArgvLookup::new(args(&["tool", "--host", "localhost", "--port", "8080"]));
+        let template = Template::new("$host$:$port$").unwrap();
+        assert_eq!(template.render(&lookup), "localhost:8080");
+    }
+
+    #[test]
+    fn argv_lookup_reads_inline_values() {
+        let lookup = ArgvLookup::new(args(&["--host=localhost", "--url=a=b"]));
+        assert_eq!(lookup.lookup("host"), Some("localhost"));
+        assert_eq!(lookup.lookup("url"), Some("a=b"));
+    }
+
+    #[test]
+    fn argv_lookup_flag_without_value_is_empty() {
+        let lookup = ArgvLookup::new(args(&["--verbose", "--level", "3", "--dry-run"]));
+        assert_eq!(lookup.lookup("verbose"), Some(""));
+        assert_eq!(lookup.lookup("level"), Some("3"));
+        assert_eq!(lookup.lookup("dry-run"), Some(""));
+    }
+
+    #[test]
+    fn argv_lookup_stops_at_end_of_options() {
+        let lookup = ArgvLookup::new(args(&["--verbose", "--", "--host", "localhost"]));
+        assert_eq!(lookup.lookup("verbose"), Some(""));
+        assert_eq!(lookup.lookup(""), None);
+        assert_eq!(lookup.lookup("host"), None);
+    }
+
+    #[test]
+    fn argv_lookup_missing_key() {
+        let lookup = ArgvLookup::new(args(&["--host", "localhost"]));
+        assert_eq!(lookup.lookup("port"), None);
+        assert_eq!(lookup.lookup("localhost"), None);
+    }
+
+    #[test]
+    fn size_hint_is_length_of_constants() {
+        let template = Template::new("Hello $who$, $$5!").unwrap();
+        assert_eq!(template.size_hint(), "Hello ".len() + ", $5!".len());
+
+        let template = template.set("who", "world");
+        assert_eq!(template.size_hint(), "Hello world, $5!".len());
+    }
+
+    #[test]
+    fn render_aligned_pads_short_values() {
+        let template = Template::new("[$name:6$][$name:-6$]").unwrap();
+        assert_eq!(
+            template.render_aligned(&SingleLookup::new("name", "abc")),
+            "[   abc][abc   ]"
+        );
+    }
+
+    #[test]
+    fn render_aligned_keeps_values_of_exact_width() {
+        let template = Template::new("[$name:3$]").unwrap();
+        assert_eq!(template.render_aligned(&SingleLookup::new("name", "abc")), "[abc]");
+    }
+
+    #[test]
+    fn render_aligned_truncates_long_values() {
+        let template = Template::new("[$name:4$][$name:-2$]").unwrap();
+        assert_eq!(
+            template.render_aligned(&SingleLookup::new("name", "abcdefgh")),
+            "[abcd][ab]"
+        );
+    }
+
+    #[test]
+    fn render_aligned_ignores_non_numeric_spec() {
+        let template = Template::new("$a:b$ $name$").unwrap();
+        let mut map = HashMap::new();
+        map.insert("a", "x");
+        map.insert("a:b", "whole");
+        map.insert("name", "y");
+        assert_eq!(template.render_aligned(&map), "x y");
+    }
+
+    #[test]
+    fn chunks_merge_adjacent_constants() {
+        let template = Template::new("Hello $who$, $greeting$ $name$").unwrap();
+        let template = template.set("who", "world").set("greeting", "welcome");
+
+        assert_eq!(
+            template.chunks(),
+            vec![
+                Chunk::Literal(String::from("Hello world, welcome ")),
+                Chunk::Variable(String::from("name")),
+            ]
+        );
+    }
+
+    #[test]
+    fn chain_owns_its_lookups() {
+        let lookup = chain(vec![
+            Box::new(SingleLookup::new("alfa", "beta")),
+            Box::new(SingleLookup::new("who", "world")),
+            Box::new(ConstantLookup::new(String::from("fallback"))),
+        ]);
+
+        assert_eq!(lookup.lookup("alfa"), Some("beta"));
+        assert_eq!(lookup.lookup("who"), Some("world"));
+        assert_eq!(lookup.lookup("other"), Some("fallback"));
+    }
+
+    #[test]
+    fn normalize_merges_equivalent_templates() {
+        let parsed = Template::new("a$$b$v$").unwrap();
+        let resolved = Template::new("$p$b$v$").unwrap().set("p", "a$");
+        assert_ne!(parsed, resolved);
+        assert_eq!(parsed.normalize(), resolved.normalize());
+
+        let empty = Template::new("$p$$v$").unwrap().set("p", "");
+        assert_eq!(empty.normalize(), Template::new("$v$").unwrap());
+    }
+
+    #[test]
+    fn render_observing_reports_each_resolution() {
+        let template = Template::new("$a$-$b$-$a$").unwrap();
+        let mut observed = Vec::new();
+
+        let output = template.render_observing(&SingleLookup::new("a", "1"), |name, value| {
+            observed.push((name.to_string(), value.map(|value| value.to_string())))
+        });
+
+        assert_eq!(output, "1--1");
+        assert_eq!(
+            observed,
+            vec![
+                (String::from("a"), Some(String::from("1"))),
+                (String::from("b"), None),
+                (String::from("a"), Some(String::from("1"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn optional_lookup_layers() {
+        let single = SingleLookup::new("who", "world");
+        let present: Option<&dyn Lookup> = Some(&single);
+        let absent: Option<&dyn Lookup> = None;
+
+        assert_eq!(present.lookup("who"), Some("world"));
+        assert_eq!(absent.lookup("who"), None);
+
+        let mut lookup = ChainedLookup::new();
+        lookup.add(&absent);
+        lookup.add(&present);
+        assert_eq!(Template::new("$who$").unwrap().render(&lookup), "world");
+    }
+
+    #[test]
+    fn partial_except_keeps_named_variables() {
+        let mut map = HashMap::new();
+        map.insert("a", "1");
+        map.insert("b", "2");
+        map.insert("c", "3");
+        let mut keep = HashSet::new();
+        keep.insert(String::from("b"));
+
+        let template = Template::new("$a$ $b$ $c$").unwrap().partial_except(&map, &keep);
+
+        assert_eq!(template.as_spec(), "1 $b$ 3");
+        assert_eq!(template.render(&map), "1 2 3");
+    }
+
+    #[test]
+    fn partial_only_resolves_named_variables() {
+        let map = owned_map(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let mut only = HashSet::new();
+        only.insert(String::from("b"));
+
+        let template = Template::new("$a$ $b$ $c$").unwrap().partial_only(&map, &only);
+
+        assert_eq!(template.as_spec(), "$a$ 2 $c$");
+        assert_eq!(template.render(&map), "1 2 3");
+    }
+
+    #[test]
+    fn lenient_parse_keeps_unterminated_dollar_as_text() {
+        let template = Template::new_lenient("costs $5 today").unwrap();
+        assert_eq!(template.render(&EmptyLookup::new()), "costs $5 today");
+        assert_eq!(
+            Template::new("costs $5 today"),
+            Err(ParseError::UNTERMINATED_VARIABLE)
+        );
+    }
+
+    #[test]
+    fn lenient_parse_still_parses_variables() {
+        let template = Template::new_lenient("$x$").unwrap();
+        assert_eq!(template, Template::new("$x$").unwrap());
+        assert_eq!(template.render(&SingleLookup::new("x", "value")), "value");
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let templates = vec![
+            Template::new("").unwrap(),
+            Template::new("Hello $who$!").unwrap(),
+            Template::new("$$5 for $item$ $$").unwrap(),
+            Template::new("$a$$b$").unwrap().set("a", "$"),
+        ];
+        for template in templates {
+            assert_eq!(Template::from_tokens(template.to_tokens()), template);
+        }
+
+        let template = Template::new("a $b$").unwrap();
+        assert_eq!(
+            template.to_tokens(),
+            vec![(false, String::from("a ")), (true, String::from("b"))]
+        );
+    }
+
+    fn owned_map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn layered_lookup_reports_shadowed_keys() {
+        let mut lookup = LayeredLookup::new();
+        lookup
+            .add(owned_map(&[("port", "9090")]))
+            .add(owned_map(&[("host", "localhost"), ("port", "8080")]));
+
+        assert_eq!(lookup.lookup("port"), Some("9090"));
+        assert_eq!(lookup.conflicts(), vec![String::from("port")]);
+    }
+
+    #[test]
+    fn layered_lookup_key_in_single_layer() {
+        let mut lookup = LayeredLookup::new();
+        lookup
+            .add(owned_map(&[("port", "9090")]))
+            .add(owned_map(&[("host", "localhost")]));
+
+        assert_eq!(lookup.lookup("host"), Some("localhost"));
+        assert_eq!(lookup.lookup("user"), None);
+        assert!(lookup.conflicts().is_empty());
+    }
+
+    #[test]
+    fn extract_variables_returns_distinct_names() {
+        assert_eq!(
+            extract_variables("$b$ $a$ $b$ $$ $c$"),
+            Ok(vec![String::from("b"), String::from("a"), String::from("c")])
+        );
+    }
+
+    #[test]
+    fn extract_variables_reports_parse_errors() {
+        assert_eq!(
+            extract_variables("Hello $who"),
+            Err(ParseError::UNTERMINATED_VARIABLE)
+        );
+    }
+
+    #[test]
+    fn render_budgeted_within_budget() {
+        let template = Template::new("$a$ $a$ $a$").unwrap();
+        assert_eq!(
+            template.render_budgeted(&SingleLookup::new("a", "x"), 3),
+            Ok(String::from("x x x"))
+        );
+    }
+
+    #[test]
+    fn render_budgeted_exceeding_budget() {
+        let template = Template::new("$a$ $a$ $a$").unwrap();
+        assert_eq!(
+            template.render_budgeted(&SingleLookup::new("a", "x"), 2),
+            Err(RenderError::BudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn render_owned_accepts_any_lookup() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        assert_eq!(template.render_owned(&lookup), template.render(&lookup));
+    }
+
+    #[cfg(feature = "json")]
+    fn json_object(text: &str) -> serde_json::Map<String, serde_json::Value> {
+        match serde_json::from_str(text).unwrap() {
+            serde_json::Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_map_lookup_resolves_strings() {
+        let map = json_object(r#"{"who": "world"}"#);
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(template.render_owned(&map), "Hello world!");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_map_lookup_stringifies_numbers() {
+        let map = json_object(r#"{"count": 42, "ratio": 0.5, "ok": true}"#);
+        assert_eq!(map.lookup_owned("count"), Some(String::from("42")));
+        assert_eq!(map.lookup_owned("ratio"), Some(String::from("0.5")));
+        assert_eq!(map.lookup_owned("ok"), Some(String::from("true")));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_map_lookup_missing_key() {
+        let map = json_object(r#"{"nested": {"key": "value"}, "nothing": null}"#);
+        assert_eq!(map.lookup_owned("missing"), None);
+        assert_eq!(map.lookup_owned("nested"), None);
+        assert_eq!(map.lookup_owned("nothing"), None);
+    }
+
+    #[test]
+    fn for_each_variable_mut_renames_in_place() {
+        let mut template = Template::new("Hello $who$, $$$greeting$!").unwrap();
+        template.for_each_variable_mut(|name| name.push_str("_v2"));
+        assert_eq!(template.as_spec(), "Hello $who_v2$, $$$greeting_v2$!");
+    }
+
+    #[test]
+    fn render_error_display() {
+        let missing = RenderError::MissingVariable(String::from("who"));
+        match missing {
+            RenderError::MissingVariable(ref name) => assert_eq!(name, "who"),
+            _ => panic!("unexpected variant"),
+        }
+        assert_eq!(missing.to_string(), "missing variable 'who'");
+        assert_eq!(
+            RenderError::BudgetExceeded.to_string(),
+            "substitution budget exceeded"
+        );
+        assert_eq!(
+            RenderError::RecursionLimit.to_string(),
+            "recursion limit reached"
+        );
+    }
+
+    #[test]
+    fn render_join_without_lookups_is_empty() {
+        let template = Template::new("<$item$>").unwrap();
+        assert_eq!(template.render_join(&[], ", "), "");
+    }
+
+    #[test]
+    fn render_join_single_lookup_has_no_separator() {
+        let template = Template::new("<$item$>").unwrap();
+        let lookup = SingleLookup::new("item", "a");
+        assert_eq!(template.render_join(&[&lookup], ", "), "<a>");
+    }
+
+    #[test]
+    fn render_join_separates_renders() {
+        let template = Template::new("<$item$>").unwrap();
+        let a = SingleLookup::new("item", "a");
+        let b = SingleLookup::new("item", "b");
+        let c = SingleLookup::new("item", "c");
+        assert_eq!(template.render_join(&[&a, &b, &c], ", "), "<a>, <b>, <c>");
+    }
+
+    #[test]
+    fn dotenv_lookup_parses_lines() {
+        let lookup = DotenvLookup::parse(
+            "# database settings\nDB_HOST=localhost\n\nDB_PORT = 5432\nGREETING=\"hello  world \"\n",
+        ).unwrap();
+
+        assert_eq!(lookup.lookup("DB_HOST"), Some("localhost"));
+        assert_eq!(lookup.lookup("DB_PORT"), Some("5432"));
+        assert_eq!(lookup.lookup("GREETING"), Some("hello  world "));
+        assert_eq!(lookup.lookup("DB_USER"), None);
+    }
+
+    #[test]
+    fn dotenv_lookup_rejects_invalid_lines() {
+        assert_eq!(
+            DotenvLookup::parse("A=1\nnot a pair\n").err(),
+            Some(ParseError::InvalidLine { line: 2 })
+        );
+        assert_eq!(
+            DotenvLookup::parse("=1").err(),
+            Some(ParseError::InvalidLine { line: 1 })
+        );
+    }
+
+    #[test]
+    fn structurally_eq_ignores_constant_boundaries() {
+        let resolved = Template::new("Hello $who$!").unwrap().set("who", "world");
+        let coalesced = Template::new("Hello world!").unwrap();
+
+        assert_ne!(resolved, coalesced);
+        assert!(resolved.structurally_eq(&coalesced));
+        assert!(!resolved.structurally_eq(&Template::new("Hello $who$!").unwrap()));
+    }
+
+    #[test]
+    fn partial_where_resolves_matching_variables() {
+        let lookup = ConstantLookup::new(String::from("x"));
+        let template = Template::new("$env.home$ $user$ $env.path$").unwrap();
+
+        let template = template.partial_where(&lookup, |name| name.starts_with("env."));
+
+        assert_eq!(template.as_spec(), "x $user$ x");
+    }
+
+    #[test]
+    fn lone_dollar_is_literal() {
+        assert_eq!(
+            Template::new("$").unwrap().render(&EmptyLookup::new()),
+            "$"
+        );
+    }
+
+    #[test]
+    fn trailing_dollar_is_literal() {
+        let template = Template::new("a$").unwrap();
+        assert_eq!(template.render(&EmptyLookup::new()), "a$");
+        assert_eq!(template, Template::new("a$$").unwrap().normalize());
+    }
+
+    #[test]
+    fn price_ending_in_dollar_is_literal() {
+        let template = Template::new("price: $").unwrap();
+        assert_eq!(template.render(&EmptyLookup::new()), "price: $");
+        assert!(!template.has_variables());
+        assert!(Template::is_valid("price: $"));
+    }
+
+    #[test]
+    fn dollar_pair_is_still_variable() {
+        let template = Template::new("$x$").unwrap();
+        assert_eq!(variable_names(&template), [Arc::from("x")]);
+        assert_eq!(template.render(&SingleLookup::new("x", "1")), "1");
+    }
+
+    #[test]
+    fn dollar_followed_by_name_is_unterminated() {
+        assert_eq!(Template::new("$a"), Err(ParseError::UNTERMINATED_VARIABLE));
+    }
+
+    fn nested_map_lookup() -> NestedMapLookup {
+        let mut sections = HashMap::new();
+        sections.insert(String::from("a"), owned_map(&[("b", "value"), ("c.d", "dotted")]));
+        NestedMapLookup::new(sections)
+    }
+
+    #[test]
+    fn nested_map_lookup_resolves_section_and_key() {
+        let lookup = nested_map_lookup();
+        assert_eq!(lookup.lookup("a.b"), Some("value"));
+        assert_eq!(lookup.lookup("a.c.d"), Some("dotted"));
+    }
+
+    #[test]
+    fn nested_map_lookup_missing_section() {
+        assert_eq!(nested_map_lookup().lookup("x.b"), None);
+    }
+
+    #[test]
+    fn nested_map_lookup_missing_key_in_section() {
+        assert_eq!(nested_map_lookup().lookup("a.x"), None);
+    }
+
+    #[test]
+    fn nested_map_lookup_key_without_dot() {
+        assert_eq!(nested_map_lookup().lookup("a"), None);
+    }
+
+    #[test]
+    fn render_all_matches_individual_renders() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let first = SingleLookup::new("who", "world");
+        let second = SingleLookup::new("who", "everyone out there");
+        let third = EmptyLookup::new();
+
+        assert_eq!(
+            template.render_all(&[&first, &second, &third]),
+            vec![
+                template.render(&first),
+                template.render(&second),
+                template.render(&third),
+            ]
+        );
+    }
+
+    #[test]
+    fn render_escaped_html() {
+        let template = Template::new("<p>$body$</p>").unwrap();
+        let lookup = SingleLookup::new("body", "<script>alert('x & y')</script>");
+        assert_eq!(
+            template.render_escaped(&lookup, &HtmlEscaper),
+            "<p>&lt;script&gt;alert(&#39;x &amp; y&#39;)&lt;/script&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn render_escaped_shell() {
+        let template = Template::new("echo $message$").unwrap();
+        let lookup = SingleLookup::new("message", "it's a \"test\"");
+        assert_eq!(
+            template.render_escaped(&lookup, &ShellEscaper),
+            "echo 'it'\\''s a \"test\"'"
+        );
+    }
+
+    #[test]
+    fn render_escaped_no_escape_is_identity() {
+        let template = Template::new("<$a$>").unwrap();
+        let lookup = SingleLookup::new("a", "<b> & 'c'");
+        assert_eq!(template.render_escaped(&lookup, &NoEscape), template.render(&lookup));
+    }
+
+    #[cfg(feature = "std")]
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("stt-{}-{}", std::process::id(), name))
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_file_parses_template() {
+        let path = temp_path("from_file_parses_template");
+        fs::write(&path, "Hello $who$!").unwrap();
+
+        let template = Template::from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(template.unwrap(), Template::new("Hello $who$!").unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_file_reports_parse_errors() {
+        let path = temp_path("from_file_reports_parse_errors");
+        fs::write(&path, "Hello $who").unwrap();
+
+        let template = Template::from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        match template {
+            Err(TemplateError::Parse(ParseError::UNTERMINATED_VARIABLE)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_file_reports_missing_file() {
+        match Template::from_file(temp_path("from_file_reports_missing_file")) {
+            Err(TemplateError::Io(error)) => assert_eq!(error.kind(), io::ErrorKind::NotFound),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unresolved_keeps_remaining_variables() {
+        let template = Template::new("Dear $title$ $name$, you owe $amount$.").unwrap();
+        let template = template.set("name", "Smith");
+
+        assert_eq!(template.unresolved().as_spec(), "$title$$amount$");
+        assert_eq!(template.set("title", "Dr").set("amount", "5").unresolved().as_spec(), "");
+    }
+
+    #[test]
+    fn generator_lookup_yields_new_value_per_occurrence() {
+        let mut next = 0;
+        let lookup = GeneratorLookup::new("uuid", || {
+            next += 1;
+            format!("id-{}", next)
+        });
+        let template = Template::new("$uuid$ $uuid$ $other$").unwrap();
+
+        assert_eq!(template.render_owned(&lookup), "id-1 id-2 ");
+    }
+
+    #[test]
+    fn is_valid_accepts_valid_specs() {
+        for spec in &["Hello $who$!", "$$", "$a$$$", "price: $", ""] {
+            assert!(Template::is_valid(spec), "{}", spec);
+            assert!(Template::new(spec).is_ok(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn is_valid_rejects_unterminated_specs() {
+        for spec in &["Hello $who", "$$$a", "$a$ $b"] {
+            assert!(!Template::is_valid(spec), "{}", spec);
+            assert!(Template::new(spec).is_err(), "{}", spec);
+        }
+    }
+
+    fn filters() -> HashMap<String, Filter> {
+        let mut filters: HashMap<String, Filter> = HashMap::new();
+        filters.insert(
+            String::from("reverse"),
+            Box::new(|value| value.chars().rev().collect()),
+        );
+        filters.insert(String::from("upper"), Box::new(|value| value.to_uppercase()));
+        filters
+    }
+
+    #[test]
+    fn render_with_filters_applies_named_filters() {
+        let template = Template::new("$who|reverse$ $who|reverse|upper$ $who$").unwrap();
+        assert_eq!(
+            template.render_with_filters(&SingleLookup::new("who", "world"), &filters()),
+            Ok(String::from("dlrow DLROW world"))
+        );
+    }
+
+    #[test]
+    fn render_with_filters_rejects_unknown_filter() {
+        let template = Template::new("$who|shout$").unwrap();
+        assert_eq!(
+            template.render_with_filters(&SingleLookup::new("who", "world"), &filters()),
+            Err(RenderError::UnknownFilter(String::from("shout")))
+        );
+    }
+
+    #[test]
+    fn lookup_bool_interprets_values() {
+        let mut map = HashMap::new();
+        map.insert("yes", "true");
+        map.insert("one", "1");
+        map.insert("zero", "0");
+        map.insert("no", "false");
+        map.insert("empty", "");
+        map.insert("other", "maybe");
+
+        assert_eq!(map.lookup_bool("yes"), Some(true));
+        assert_eq!(map.lookup_bool("one"), Some(true));
+        assert_eq!(map.lookup_bool("zero"), Some(false));
+        assert_eq!(map.lookup_bool("no"), Some(false));
+        assert_eq!(map.lookup_bool("empty"), Some(false));
+        assert_eq!(map.lookup_bool("other"), None);
+        assert_eq!(map.lookup_bool("missing"), None);
+    }
+
+    #[test]
+    fn push_fragments_builds_template() {
+        let mut template = Template::empty();
+        assert_eq!(template, Template::new("").unwrap());
+
+        template
+            .push_constant("Hello ")
+            .push_variable("who")
+            .push_constant(",")
+            .push_constant(" welcome!");
+
+        assert_eq!(template, Template::new("Hello $who$, welcome!").unwrap().normalize());
+        assert_eq!(template.size_hint(), "Hello , welcome!".len());
+        assert_eq!(
+            template.render(&SingleLookup::new("who", "world")),
+            "Hello world, welcome!"
+        );
+    }
+
+    #[cfg(feature = "log")]
+    struct CapturingLogger {
+        lines: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[cfg(feature = "log")]
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == "stt-test" {
+                self.lines
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", record.level(), record.args()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn logging_lookup_logs_resolutions() {
+        static LOGGER: CapturingLogger = CapturingLogger {
+            lines: std::sync::Mutex::new(Vec::new()),
+        };
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let inner = SingleLookup::new("who", "world");
+        let lookup = LoggingLookup::new(&inner, "stt-test");
+        let template = Template::new("Hello $who$$punctuation$").unwrap();
+
+        assert_eq!(template.render(&lookup), "Hello world");
+        assert_eq!(
+            *LOGGER.lines.lock().unwrap(),
+            vec![
+                String::from("DEBUG lookup 'who': hit"),
+                String::from("DEBUG lookup 'punctuation': miss"),
+            ]
+        );
+    }
+
+    #[test]
+    fn try_render_resolves_all_variables() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        assert_eq!(template.try_render(&lookup), Ok(template.render(&lookup)));
+    }
+
+    #[test]
+    fn try_render_fails_where_render_is_lenient() {
+        let template = Template::new("Hello $who$$punctuation$").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+
+        assert_eq!(template.render(&lookup), "Hello world");
+        assert_eq!(
+            template.try_render(&lookup),
+            Err(RenderError::MissingVariable(String::from("punctuation")))
+        );
+    }
+
+    #[test]
+    fn nested_default_resolves_other_variable() {
+        let options = ParseOptions::new().nested_defaults(true);
+        let template = Template::with_options("Hello $name:$fallback$$!", &options).unwrap();
+
+        assert_eq!(template.count_occurrences("name:$fallback$"), 1);
+        assert_eq!(
+            template.render_with_defaults(&SingleLookup::new("name", "Ann")),
+            "Hello Ann!"
+        );
+        assert_eq!(
+            template.render_with_defaults(&SingleLookup::new("fallback", "guest")),
+            "Hello guest!"
+        );
+    }
+
+    #[test]
+    fn nested_default_with_literal_default() {
+        let options = ParseOptions::new().nested_defaults(true);
+        let template = Template::with_options("Hello $name:you$!", &options).unwrap();
+        assert_eq!(template.render_with_defaults(&EmptyLookup::new()), "Hello you!");
+
+        let template = Template::with_options("$a:$b:$c$ or so$$", &options).unwrap();
+        assert_eq!(
+            template.render_with_defaults(&SingleLookup::new("c", "3")),
+            "3 or so"
+        );
+    }
+
+    #[test]
+    fn spec_is_cached_until_modified() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(template.spec(), template.as_spec());
+        assert_eq!(template.spec(), "Hello $who$!");
+
+        let resolved = template.set("who", "world");
+        assert_eq!(resolved.spec(), "Hello world!");
+
+        let mut template = Template::new("$a$").unwrap();
+        assert_eq!(template.spec(), "$a$");
+        template.push_constant(" and ").push_variable("b");
+        assert_eq!(template.spec(), "$a$ and $b$");
+        template.for_each_variable_mut(|name| name.make_ascii_uppercase());
+        assert_eq!(template.spec(), "$A$ and $B$");
+    }
+
+    fn arbitrary_template() -> impl Strategy<Value = Template> {
+        let token = prop_oneof![
+            "[a-z$ ]{1,5}".prop_map(|text| (false, text)),
+            r"[a-z_.:$\\]{1,5}".prop_map(|name| (true, name)),
+        ];
+        prop::collection::vec(token, 0..8)
+            .prop_map(|tokens| Template::from_tokens(tokens).normalize())
+    }
+
+    proptest! {
+        #[test]
+        fn spec_round_trips(template in arbitrary_template()) {
+            prop_assert_eq!(Template::new(&template.as_spec()), Ok(template));
+        }
+
+        #[test]
+        fn render_is_deterministic(template in arbitrary_template()) {
+            let lookup = ConstantLookup::new(String::from("$value$"));
+            let reparsed = Template::new(&template.as_spec()).unwrap();
+            prop_assert_eq!(template.render(&lookup), template.render(&lookup));
+            prop_assert_eq!(reparsed.render(&lookup), template.render(&lookup));
+        }
+    }
+
+    #[test]
+    fn spec_round_trip_regressions() {
+        let cases = [
+            vec![(false, String::from("$ "))],
+            vec![(false, String::from("a$"))],
+            vec![
+                (false, String::from("$")),
+                (true, String::from("a")),
+                (false, String::from("b$$")),
+            ],
+        ];
+        for tokens in cases.iter() {
+            let template = Template::from_tokens(tokens.clone());
+            assert_eq!(Template::new(&template.as_spec()), Ok(template));
+        }
+    }
+
+    #[test]
+    fn config_lookup_prefers_environment() {
+        env::set_var("STT_TEST_CONFIG_HOST", "example.com");
+        let lookup = ConfigLookup::new(owned_map(&[("STT_TEST_CONFIG_HOST", "localhost")]));
+        assert_eq!(lookup.lookup("STT_TEST_CONFIG_HOST"), Some("example.com"));
+    }
+
+    #[test]
+    fn config_lookup_falls_back_to_defaults() {
+        env::remove_var("STT_TEST_CONFIG_PORT");
+        let lookup = ConfigLookup::new(owned_map(&[("STT_TEST_CONFIG_PORT", "8080")]));
+        assert_eq!(lookup.lookup("STT_TEST_CONFIG_PORT"), Some("8080"));
+        assert_eq!(lookup.lookup("STT_TEST_CONFIG_USER"), None);
+    }
+
+    #[test]
+    fn render_indexed_passes_occurrence_index() {
+        let template = Template::new("$item$ $item$ $other$").unwrap();
+        assert_eq!(
+            template.render_indexed(|name, index| Some(format!("{}{}", name, index))),
+            "item0 item1 other2"
+        );
+
+        let ordinals = ["first", "second"];
+        let template = Template::new("$item$ $item$").unwrap();
+        assert_eq!(
+            template.render_indexed(|_, index| Some(ordinals[index].to_string())),
+            "first second"
+        );
+    }
+
+    #[test]
+    fn render_indexed_missing_values_render_empty() {
+        let template = Template::new("[$a$][$b$]").unwrap();
+        let values = ["x"];
+        assert_eq!(
+            template.render_indexed(|_, index| values.get(index).map(|value| value.to_string())),
+            "[x][]"
+        );
+    }
+
+    #[test]
+    fn whitespace_control_trims_around_marked_variables() {
+        let options = ParseOptions::new().whitespace_control(true);
+        let lookup = SingleLookup::new("x", "X");
+
+        let template = Template::with_options("a  $-x-$  b", &options).unwrap();
+        assert_eq!(template.render(&lookup), "aXb");
+
+        let template = Template::with_options("a \n $-x$ \n b $x-$\n c", &options).unwrap();
+        assert_eq!(template.render(&lookup), "aX \n b Xc");
+    }
+
+    #[test]
+    fn whitespace_control_leaves_unmarked_variables() {
+        let options = ParseOptions::new().whitespace_control(true);
+        let template = Template::with_options("a  $x$  b", &options).unwrap();
+        assert_eq!(template.render(&SingleLookup::new("x", "X")), "a  X  b");
+
+        let template = Template::new("a  $-x-$  b").unwrap();
+        assert_eq!(template.count_occurrences("-x-"), 1);
+    }
+
+    #[test]
+    fn vars_macro_builds_lookup() {
+        let template = Template::new("Hello $who$, I am $name$!").unwrap();
+        let name = String::from("x");
+        let lookup = vars! {
+            who => "world",
+            name => name,
+        };
+        assert_eq!(template.render(&lookup), "Hello world, I am x!");
+    }
+
+    #[test]
+    fn vars_macro_without_variables() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(template.render(&vars! {}), "Hello !");
+    }
+
+    #[test]
+    fn starts_and_ends_with_variable() {
+        let template = Template::new("$x$ tail").unwrap();
+        assert!(template.starts_with_variable());
+        assert!(!template.ends_with_variable());
+
+        let template = Template::new("head $x$").unwrap();
+        assert!(!template.starts_with_variable());
+        assert!(template.ends_with_variable());
+
+        let template = Template::new("constant").unwrap();
+        assert!(!template.starts_with_variable());
+        assert!(!template.ends_with_variable());
+    }
+
+    fn variable_names(template: &Template) -> Vec<Arc<str>> {
+        template
+            .fragments
+            .iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Variable(var) => Some(var.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn variable_names_are_shared_within_template() {
+        let names = variable_names(&Template::new("$x$ and $y$ and $x$").unwrap());
+        assert!(Arc::ptr_eq(&names[0], &names[2]));
+        assert!(!Arc::ptr_eq(&names[0], &names[1]));
+    }
+
+    #[test]
+    fn variable_names_are_shared_through_pool() {
+        let mut pool = NamePool::new();
+        let options = ParseOptions::new();
+        let first = Template::with_pool("$x$", &options, &mut pool).unwrap();
+        let second = Template::with_pool("Hello $x$", &options, &mut pool).unwrap();
+
+        assert!(Arc::ptr_eq(&variable_names(&first)[0], &variable_names(&second)[0]));
+    }
+
+    #[test]
+    fn render_into_accepts_maps() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(
+            template.render_into(owned_map(&[("who", "world")])),
+            "Hello world!"
+        );
+
+        let mut map = HashMap::new();
+        map.insert("who", "there");
+        assert_eq!(template.render_into(map), "Hello there!");
+    }
+
+    #[test]
+    fn render_into_accepts_pairs() {
+        let template = Template::new("$a$ $b$").unwrap();
+        assert_eq!(template.render_into(&[("a", "1"), ("b", "2")]), "1 2");
+
+        let pairs = vec![("a", "x")];
+        assert_eq!(template.render_into(pairs.as_slice()), "x ");
+    }
+
+    #[test]
+    fn render_into_accepts_closures() {
+        let template = Template::new("$a$ $b$").unwrap();
+        assert_eq!(
+            template.render_into(|key: &str| Some(key.to_uppercase())),
+            "A B"
+        );
+    }
+
+    #[test]
+    fn render_into_accepts_lookups() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        assert_eq!(template.render_into(&lookup as &dyn Lookup), "Hello world!");
+    }
+
+    #[test]
+    fn word_names_accept_word_characters() {
+        let options = ParseOptions::new().word_names(true);
+        let template = Template::with_options("Hello $first_name2$!", &options).unwrap();
+        assert_eq!(
+            template.render(&SingleLookup::new("first_name2", "Ada")),
+            "Hello Ada!"
+        );
+    }
+
+    #[test]
+    fn word_names_reject_spaces() {
+        let options = ParseOptions::new().word_names(true);
+        assert_eq!(
+            Template::with_options("costs $5 or $6", &options),
+            Err(ParseError::InvalidVariableName {
+                name: "5 or ".to_string()
+            })
+        );
+        assert!(Template::with_options("$a b$", &options).is_err());
+    }
+
+    #[test]
+    fn names_are_unrestricted_by_default() {
+        let template = Template::new("$a b$").unwrap();
+        assert_eq!(template.render(&SingleLookup::new("a b", "ok")), "ok");
+    }
+
+    #[test]
+    fn render_reusing_matches_render() {
+        let mut buffer = RenderBuffer::new();
+        for size in [3, 50, 0, 500, 10] {
+            let spec = format!("{}$a$-$b$", "x".repeat(size));
+            let template = Template::new(&spec).unwrap();
+            let value = "y".repeat(size % 7);
+            let lookup = owned_map(&[("a", &value), ("b", "z")]);
+            let expected = template.render(&lookup);
+            assert_eq!(template.render_reusing(&mut buffer, &lookup), expected);
+            assert_eq!(buffer.as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn accept_visits_variables_in_order() {
+        struct Names(Vec<String>);
+
+        impl Visitor for Names {
+            fn visit_constant(&mut self, _: &str) {}
+
+            fn visit_variable(&mut self, name: &str) {
+                self.0.push(name.to_string());
+            }
+        }
+
+        let template = Template::new("$b$ and $a$ and $b$").unwrap();
+        let mut names = Names(Vec::new());
+        template.accept(&mut names);
+        assert_eq!(names.0, ["b", "a", "b"]);
+    }
+
+    #[test]
+    fn accept_visits_constants() {
+        struct Counter(usize);
+
+        impl Visitor for Counter {
+            fn visit_constant(&mut self, _: &str) {
+                self.0 += 1;
+            }
+
+            fn visit_variable(&mut self, _: &str) {}
+        }
+
+        let template = Template::new("a $x$ b $y$$z$").unwrap();
+        let mut counter = Counter(0);
+        template.accept(&mut counter);
+        assert_eq!(counter.0, 2);
+    }
+
+    fn int_keyed_lookup() -> IntKeyedLookup {
+        let mut values = HashMap::new();
+        values.insert(1, "one".to_string());
+        values.insert(42, "answer".to_string());
+        IntKeyedLookup::new(values)
+    }
+
+    #[test]
+    fn int_keyed_lookup_resolves_numeric_keys() {
+        let template = Template::new("$42$ and $1$").unwrap();
+        assert_eq!(template.render(&int_keyed_lookup()), "answer and one");
+    }
+
+    #[test]
+    fn int_keyed_lookup_ignores_non_numeric_keys() {
+        assert_eq!(int_keyed_lookup().lookup("one"), None);
+        assert_eq!(int_keyed_lookup().lookup("-1"), None);
+    }
+
+    #[test]
+    fn int_keyed_lookup_misses_absent_numbers() {
+        assert_eq!(int_keyed_lookup().lookup("7"), None);
+    }
+
+    #[test]
+    fn assert_render_accepts_matching_output() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        assert_eq!(template.assert_render(&lookup, "Hello world!"), Ok(()));
+    }
+
+    #[test]
+    fn assert_render_reports_first_difference() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        let mismatch = template.assert_render(&lookup, "Hello word!").unwrap_err();
+        assert_eq!(mismatch.offset, 9);
+        assert_eq!(mismatch.expected, "Hello word!");
+        assert_eq!(mismatch.actual, "Hello world!");
+
+        let mismatch = template.assert_render(&lookup, "Hello world!!").unwrap_err();
+        assert_eq!(mismatch.offset, 12);
+    }
+
+    #[test]
+    fn display_with_matches_render() {
+        let template = Template::new("Hello $who$, $$5 $missing$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        assert_eq!(
+            format!("[{}]", template.display_with(&lookup)),
+            format!("[{}]", template.render(&lookup))
+        );
+    }
+
+    #[test]
+    fn new_smart_keeps_lone_dollars() {
+        let template = Template::new_smart("$5.00 and $who$").unwrap();
+        assert_eq!(
+            template.render(&SingleLookup::new("who", "you")),
+            "$5.00 and you"
+        );
+        assert_eq!(variable_names(&template), [Arc::from("who")]);
+    }
+
+    #[test]
+    fn new_smart_still_unescapes_double_dollars() {
+        let template = Template::new_smart("$$ $ $$a $").unwrap();
+        assert_eq!(template.render(&EmptyLookup {}), "$ $ $a $");
+    }
+
+    #[test]
+    fn render_env_expands_set_variables() {
+        env::set_var("STT_TEST_RENDER_ENV", "expanded");
+        assert_eq!(
+            render_env("value: $STT_TEST_RENDER_ENV$").unwrap(),
+            "value: expanded"
+        );
+    }
+
+    #[test]
+    fn render_env_leaves_unset_variables_empty() {
+        env::remove_var("STT_TEST_RENDER_ENV_UNSET");
+        assert_eq!(render_env("[$STT_TEST_RENDER_ENV_UNSET$]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn render_env_reports_parse_errors() {
+        match render_env("$HOME") {
+            Err(TemplateError::Parse(ParseError::UNTERMINATED_VARIABLE)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn terminators_close_variables_at_end_of_line() {
+        let options = ParseOptions::new().terminators(&['\n']);
+        let template = Template::with_options("Hello $name\nbye $\n", &options).unwrap();
+        assert_eq!(variable_names(&template), [Arc::from("name")]);
+        assert_eq!(
+            template.render(&SingleLookup::new("name", "Ada")),
+            "Hello Ada\nbye $\n"
+        );
+    }
+
+    #[test]
+    fn terminators_still_close_at_dollar() {
+        let options = ParseOptions::new().terminators(&['\n']);
+        let template = Template::with_options("$name$ rest\n", &options).unwrap();
+        assert_eq!(
+            template.render(&SingleLookup::new("name", "Ada")),
+            "Ada rest\n"
+        );
+    }
+
+    #[test]
+    fn render_cow_mixes_borrowed_and_owned_values() {
+        let names = owned_map(&[("name", "Ada")]);
+        let lookup = CowFnLookup::new(|key: &str| match key {
+            "count" => Some(Cow::Owned(format!("{} items", 3))),
+            _ => names.get(key).map(|value| Cow::Borrowed(value.as_str())),
+        });
+        let template = Template::new("$name$ has $count$$missing$.").unwrap();
+        assert_eq!(template.render_cow(&lookup), "Ada has 3 items.");
+    }
+
+    #[test]
+    fn render_cow_accepts_plain_lookups() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(
+            template.render_cow(&SingleLookup::new("who", "world")),
+            "Hello world!"
+        );
+    }
+
+    #[test]
+    fn minify_whitespace_collapses_indentation() {
+        let template = Template::new("<ul>\n    <li>$item$</li>\n\t\t</ul>\n").unwrap();
+        assert_eq!(
+            template.minify_whitespace().as_spec(),
+            "<ul> <li>$item$</li> </ul> "
+        );
+    }
+
+    #[test]
+    fn minify_whitespace_leaves_variables_untouched() {
+        let template = Template::new("a  $two  words$  b").unwrap();
+        let minified = template.minify_whitespace();
+        assert_eq!(variable_names(&minified), [Arc::from("two  words")]);
+        assert_eq!(
+            minified.render(&SingleLookup::new("two  words", "x  \n  y")),
+            "a x  \n  y b"
+        );
+    }
+
+    #[test]
+    fn braces_parse_balanced_variables() {
+        let options = ParseOptions::new().braces(true);
+        let template = Template::with_options("Hi ${name}, $$ ${x}$y$", &options).unwrap();
+        assert_eq!(template.as_spec(), "Hi $name$, $$ $x$$y$");
+    }
+
+    #[test]
+    fn braces_report_unterminated_brace_offset() {
+        let options = ParseOptions::new().braces(true);
+        assert_eq!(
+            Template::with_options("${name", &options),
+            Err(ParseError::UnterminatedBrace { offset: 0 })
+        );
+        assert_eq!(
+            Template::with_options("héllo ${name", &options),
+            Err(ParseError::UnterminatedBrace { offset: 7 })
+        );
+        assert_eq!(
+            Template::with_options("$name", &options),
+            Err(ParseError::UNTERMINATED_VARIABLE)
+        );
+    }
+
+    #[test]
+    fn bake_freezes_known_values() {
+        let template = Template::new("Dear $title$ $name$, $greeting$!").unwrap();
+        let baked = template.bake(&owned_map(&[("title", "Dr."), ("greeting", "welcome")]));
+        assert_eq!(baked.as_spec(), "Dear Dr. $name$, welcome!");
+        assert_eq!(baked.fragments.len(), 3);
+        assert_eq!(
+            baked.render(&SingleLookup::new("name", "Who")),
+            "Dear Dr. Who, welcome!"
+        );
+    }
+
+    #[test]
+    fn covers_all_variables() {
+        let template = Template::new("$a$ $b$ $a$").unwrap();
+        assert!(covers(&template, &owned_map(&[("a", "1"), ("b", "2"), ("c", "3")])));
+    }
+
+    #[test]
+    fn covers_detects_missing_variable() {
+        let template = Template::new("$a$ $b$").unwrap();
+        assert!(!covers(&template, &SingleLookup::new("a", "1")));
+    }
+
+    #[test]
+    fn covers_constant_templates() {
+        let template = Template::new("no variables, $$5").unwrap();
+        assert!(covers(&template, &EmptyLookup {}));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_rewrite_lookup_rewrites_keys() {
+        let inner = SingleLookup::new("name", "Ada");
+        let pattern = regex::Regex::new("^ctx_").unwrap();
+        let lookup = RegexRewriteLookup::new(pattern, "", &inner);
+        assert_eq!(lookup.lookup("ctx_name"), Some("Ada"));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_rewrite_lookup_passes_other_keys_through() {
+        let inner = owned_map(&[("name", "Ada"), ("other_ctx_name", "x")]);
+        let pattern = regex::Regex::new("^ctx_(.*)$").unwrap();
+        let lookup = RegexRewriteLookup::new(pattern, "$1", &inner);
+        assert_eq!(lookup.lookup("ctx_name"), Some("Ada"));
+        assert_eq!(lookup.lookup("other_ctx_name"), Some("x"));
+        assert_eq!(lookup.lookup("missing"), None);
+    }
+
+    #[test]
+    fn new_unique_rejects_repeated_variables() {
+        assert_eq!(
+            Template::new_unique("<input id=\"$id$\"> <label for=\"$name$\">$id$"),
+            Err(ParseError::DuplicateVariable {
+                name: "id".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn new_unique_accepts_distinct_variables() {
+        let template = Template::new_unique("$a$ $b$ $$ $c$").unwrap();
+        assert_eq!(template.as_spec(), "$a$ $b$ $$ $c$");
+    }
+
+    #[test]
+    fn write_spec_appends_to_buffer() {
+        let first = Template::new("costs $$5 for $who$").unwrap();
+        let second = Template::new("$$$a$$$").unwrap();
+        let mut buf = String::from("> ");
+        first.write_spec(&mut buf);
+        second.write_spec(&mut buf);
+        assert_eq!(buf, format!("> {}{}", first.as_spec(), second.as_spec()));
+        assert_eq!(buf, "> costs $$5 for $who$$$$a$$$");
+    }
+
+    #[cfg(feature = "regex")]
+    fn captures_lookup(haystack: &str) -> CapturesLookup<'_> {
+        let pattern = regex::Regex::new(r"(\w+)@(?P<domain>\w+)(?P<tld>\.com)?").unwrap();
+        CapturesLookup::new(pattern.captures(haystack).unwrap())
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn captures_lookup_resolves_numbered_groups() {
+        let template = Template::new("$1$ at $2$ ($0$)").unwrap();
+        assert_eq!(
+            template.render(&captures_lookup("mail ada@example now")),
+            "ada at example (ada@example)"
+        );
+        assert_eq!(captures_lookup("ada@example").lookup("4"), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn captures_lookup_resolves_named_groups() {
+        assert_eq!(captures_lookup("ada@example").lookup("domain"), Some("example"));
+        assert_eq!(captures_lookup("ada@example").lookup("tld"), None);
+        assert_eq!(captures_lookup("ada@example").lookup("user"), None);
+    }
+
+    #[test]
+    fn render_lossy_without_missing_variables() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(
+            template.render_lossy(&SingleLookup::new("who", "world"), "???"),
+            "Hello world!"
+        );
+    }
+
+    #[test]
+    fn render_lossy_marks_missing_variables() {
+        let template = Template::new("$who$ owes $amount$").unwrap();
+        assert_eq!(
+            template.render_lossy(&SingleLookup::new("who", "Ada"), "???"),
+            "Ada owes ???"
+        );
+    }
+
+    #[test]
+    fn render_lossy_with_empty_marker_renders_like_render() {
+        let template = Template::new("$who$ owes $amount$").unwrap();
+        let lookup = SingleLookup::new("who", "Ada");
+        assert_eq!(template.render_lossy(&lookup, ""), template.render(&lookup));
+    }
+
+    #[test]
+    fn max_name_len_accepts_short_names() {
+        let options = ParseOptions::new().max_name_len(8);
+        let template = Template::with_options("$$ $name$ $abcdefgh$", &options).unwrap();
+        assert_eq!(template.as_spec(), "$$ $name$ $abcdefgh$");
+    }
+
+    #[test]
+    fn max_name_len_rejects_long_names() {
+        let options = ParseOptions::new().max_name_len(8);
+        assert_eq!(
+            Template::with_options("ok $a$, $abcdefghijkl$", &options),
+            Err(ParseError::VariableNameTooLong { offset: 8, len: 12 })
+        );
+        let options = options.braces(true);
+        assert_eq!(
+            Template::with_options("${abcdefghi}", &options),
+            Err(ParseError::VariableNameTooLong { offset: 0, len: 9 })
+        );
+    }
+
+    #[test]
+    fn into_fragments_round_trips() {
+        let template = Template::new("costs $$5 for $who$ and $who$").unwrap();
+        let fragments = template.clone().into_fragments();
+        assert_eq!(
+            fragments,
+            [
+                PublicFragment::Constant("costs $5 for ".to_string()),
+                PublicFragment::Variable("who".to_string()),
+                PublicFragment::Constant(" and ".to_string()),
+                PublicFragment::Variable("who".to_string()),
+            ]
+        );
+        assert_eq!(Template::from_fragments(fragments), template);
+    }
+
+    #[test]
+    fn from_fragments_rebuilds_modified_fragments() {
+        let mut fragments = Template::new("Hello $who$, $$$n$!").unwrap().into_fragments();
+        fragments.retain(|fragment| matches!(fragment, PublicFragment::Constant(_)));
+        let template = Template::from_fragments(fragments);
+        assert!(!template.has_variables());
+        assert_eq!(template.as_spec(), "Hello , $$!");
+    }
+
+    #[test]
+    fn non_empty_lookup_treats_empty_values_as_missing() {
+        let inner = owned_map(&[("name", "")]);
+        let fallback = SingleLookup::new("name", "fallback");
+        let lookup = NonEmptyLookup::new(&inner);
+        assert_eq!(lookup.lookup("name"), None);
+
+        let mut chain = ChainedLookup::new();
+        chain.add(&lookup);
+        chain.add(&fallback);
+        assert_eq!(chain.lookup("name"), Some("fallback"));
+    }
+
+    #[test]
+    fn non_empty_lookup_passes_values_through() {
+        let inner = SingleLookup::new("name", "Ada");
+        assert_eq!(NonEmptyLookup::new(&inner).lookup("name"), Some("Ada"));
+        assert_eq!(NonEmptyLookup::new(&inner).lookup("other"), None);
+    }
+
+    #[test]
+    fn render_catch_reports_panicking_lookup() {
+        struct Fragile;
+
+        impl Lookup for Fragile {
+            fn lookup(&self, key: &str) -> Option<&str> {
+                match key {
+                    "boom" => panic!("cannot resolve"),
+                    _ => Some("ok"),
+                }
+            }
+        }
+
+        let template = Template::new("$a$ $boom$").unwrap();
+        assert_eq!(
+            template.render_catch(&Fragile),
+            Err(RenderError::LookupPanicked("boom".to_string()))
+        );
+        let template = Template::new("$a$ $b$").unwrap();
+        assert_eq!(template.render_catch(&Fragile), Ok("ok ok".to_string()));
+    }
+
+    #[test]
+    fn render_with_fallback_names_missing_variables() {
+        let template = Template::new("$greeting$, $name$!").unwrap();
+        let fallback = Template::new("[missing:$var$]").unwrap();
+        assert_eq!(
+            template.render_with_fallback(&SingleLookup::new("greeting", "Hi"), &fallback),
+            "Hi, [missing:name]!"
+        );
+    }
+
+    #[test]
+    fn render_with_fallback_resolves_other_variables() {
+        let template = Template::new("$a$ $b$").unwrap();
+        let fallback = Template::new("<$var$ in $env$>").unwrap();
+        let lookup = owned_map(&[("a", "1"), ("env", "prod")]);
+        assert_eq!(
+            template.render_with_fallback(&lookup, &fallback),
+            "1 <b in prod>"
+        );
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn to_regex_captures_variables() {
+        let regex = Template::new("/users/$id$").unwrap().to_regex().unwrap();
+        let captures = regex.captures("/users/42").unwrap();
+        assert_eq!(&captures["id"], "42");
+        assert!(!regex.is_match("/groups/42"));
+        assert_eq!(&regex.captures("/users/").unwrap()["id"], "");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn to_regex_matches_empty_values() {
+        let template = Template::new("a$x$b").unwrap();
+        assert!(template.to_regex().unwrap().is_match("ab"));
+        assert_eq!(template.extract("ab"), Some(owned_map(&[("x", "")])));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn to_regex_escapes_constants() {
+        let regex = Template::new("$a$.$b$ (+$$)").unwrap().to_regex().unwrap();
+        let captures = regex.captures("x.y.z (+$)").unwrap();
+        assert_eq!((&captures["a"], &captures["b"]), ("x", "y.z"));
+        assert!(!regex.is_match("xay (+$)"));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn extract_captures_variable_values() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(
+            template.extract("Hello world!"),
+            Some(owned_map(&[("who", "world")]))
+        );
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn extract_rejects_non_matching_input() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(template.extract("Goodbye world!"), None);
+    }
+
+    #[test]
+    fn render_or_else_replaces_missing_variables() {
+        let template = Template::new("$who$ owes $amount$").unwrap();
+        let mut missing = Vec::new();
+        let rendered = template.render_or_else(&SingleLookup::new("who", "Ada"), |name| {
+            missing.push(name.to_string());
+            name.to_uppercase()
+        });
+        assert_eq!(rendered, "Ada owes AMOUNT");
+        assert_eq!(missing, ["amount"]);
+    }
+
+    #[test]
+    fn utf16_round_trips_ascii() {
+        let spec: Vec<u16> = "Hello $who$!".encode_utf16().collect();
+        let template = Template::from_utf16(&spec).unwrap();
+        let rendered = template.render_utf16(&SingleLookup::new("who", "world"));
+        assert_eq!(String::from_utf16(&rendered).unwrap(), "Hello world!");
+    }
+
+    #[test]
+    fn utf16_round_trips_non_ascii() {
+        let spec: Vec<u16> = "Grüße, $wer$ €".encode_utf16().collect();
+        let template = Template::from_utf16(&spec).unwrap();
+        let rendered = template.render_utf16(&SingleLookup::new("wer", "Jörg"));
+        assert_eq!(rendered, "Grüße, Jörg €".encode_utf16().collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn from_utf16_reports_invalid_input() {
+        match Template::from_utf16(&[0x48, 0xD800]) {
+            Err(TemplateError::Utf16(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "chrono")]
+    fn fixed_clock_lookup() -> DateTimeLookup {
+        use chrono::TimeZone;
+        DateTimeLookup::with_clock("now", || {
+            chrono::Utc.with_ymd_and_hms(2024, 3, 9, 14, 5, 30).unwrap()
+        })
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn date_time_lookup_formats_with_spec() {
+        let template = Template::new("[$now:%Y-%m-%d %H:%M$] started").unwrap();
+        assert_eq!(
+            template.render_owned(&fixed_clock_lookup()),
+            "[2024-03-09 14:05] started"
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn date_time_lookup_defaults_to_rfc3339() {
+        let lookup = fixed_clock_lookup();
+        assert_eq!(
+            lookup.lookup_owned("now"),
+            Some("2024-03-09T14:05:30+00:00".to_string())
+        );
+        assert_eq!(lookup.lookup_owned("then"), None);
+        assert_eq!(lookup.lookup_owned("now:%Q"), None);
+    }
+
+    #[test]
+    fn split_at_variable_returns_sections() {
+        let template = Template::new("intro $a$$---$body$---$ outro $b$").unwrap();
+        let sections: Vec<String> = template
+            .split_at_variable("---")
+            .iter()
+            .map(|section| section.as_spec())
+            .collect();
+        assert_eq!(sections, ["intro $a$", "body", " outro $b$"]);
+    }
+
+    #[test]
+    fn split_at_variable_without_marker() {
+        let template = Template::new("no $marker$ here").unwrap();
+        let sections = template.split_at_variable("---");
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0], template);
+    }
+
+    fn sorted_vec_lookup() -> SortedVecLookup {
+        let pairs = [("zeta", "z"), ("alpha", "a"), ("mid", "m"), ("alpha", "again")];
+        SortedVecLookup::new(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn sorted_vec_lookup_resolves_unsorted_input() {
+        let template = Template::new("$zeta$ $alpha$ $mid$").unwrap();
+        assert_eq!(template.render(&sorted_vec_lookup()), "z a m");
+    }
+
+    #[test]
+    fn sorted_vec_lookup_misses_absent_keys() {
+        assert_eq!(sorted_vec_lookup().lookup("beta"), None);
+        assert_eq!(sorted_vec_lookup().lookup(""), None);
+    }
+
+    #[test]
+    fn render_chunks_concatenate_to_render() {
+        let template = Template::new("Hello $who$, $$5 $missing$!").unwrap();
+        let lookup = SingleLookup::new("who", "world");
+        let chunks: Vec<&str> = template.render_chunks(&lookup).collect();
+        assert_eq!(chunks, ["Hello ", "world", ", $5 ", "!"]);
+        assert_eq!(chunks.concat(), template.render(&lookup));
+    }
+
+    #[test]
+    fn braces_allow_balanced_nested_braces() {
+        let options = ParseOptions::new().braces(true);
+        let template = Template::with_options("${a{b}} ${{x}{y}}", &options).unwrap();
+        assert_eq!(template.as_spec(), "$a{b}$ ${x}{y}$");
+    }
+
+    #[test]
+    fn braces_allow_escaped_braces() {
+        let options = ParseOptions::new().braces(true);
+        let template = Template::with_options(r"${a\}b} ${c\\}", &options).unwrap();
+        assert_eq!(template.as_spec(), r"$a}b$ $c\\$");
+    }
+
+    #[test]
+    fn braces_report_unbalanced_nested_braces() {
+        let options = ParseOptions::new().braces(true);
+        assert_eq!(
+            Template::with_options("x ${a{b}", &options),
+            Err(ParseError::UnterminatedBrace { offset: 2 })
+        );
+        assert_eq!(
+            Template::with_options(r"${a\", &options),
+            Err(ParseError::UnterminatedBrace { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn render_capped_within_cap() {
+        let template = Template::new("Hello $who$!").unwrap();
+        assert_eq!(
+            template.render_capped(&SingleLookup::new("who", "world"), 12),
+            Ok("Hello world!".to_string())
+        );
+    }
+
+    #[test]
+    fn render_capped_rejects_large_values() {
+        let template = Template::new("Hello $who$!").unwrap();
+        let huge = "x".repeat(1000);
+        assert_eq!(
+            template.render_capped(&SingleLookup::new("who", &huge), 100),
+            Err(RenderError::OutputTooLarge)
+        );
+        assert_eq!(
+            template.render_capped(&SingleLookup::new("who", "world"), 11),
+            Err(RenderError::OutputTooLarge)
+        );
+    }
+
+    #[test]
+    fn concat_lookup_joins_all_values() {
+        let first = owned_map(&[("tags", "rust"), ("name", "stt")]);
+        let second = SingleLookup::new("other", "x");
+        let third = SingleLookup::new("tags", "templates");
+        let mut lookup = ConcatLookup::new(", ");
+        lookup.add(&first).add(&second).add(&third);
+        let template = Template::new("$name$: $tags$").unwrap();
+        assert_eq!(template.render_owned(&lookup), "stt: rust, templates");
+    }
+
+    #[test]
+    fn concat_lookup_misses_unresolved_keys() {
+        let first = SingleLookup::new("a", "1");
+        let mut lookup = ConcatLookup::new(", ");
+        lookup.add(&first);
+        assert_eq!(lookup.lookup_owned("b"), None);
+    }
+
+    #[test]
+    fn with_blocks_fills_named_blocks() {
+        let base = Template::new("<main>$block:content$</main><aside>$block:aside$</aside>")
+            .unwrap();
+        let mut blocks = HashMap::new();
+        blocks.insert(
+            "content".to_string(),
+            Template::new("<p>Hi $name$</p>").unwrap(),
+        );
+        let page = base.with_blocks(&blocks);
+        assert_eq!(
+            page.as_spec(),
+            "<main><p>Hi $name$</p></main><aside>$block:aside$</aside>"
+        );
+        assert_eq!(
+            page.render(&SingleLookup::new("name", "Ada")),
+            "<main><p>Hi Ada</p></main><aside></aside>"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn render_timed_records_slow_lookups() {
+        struct Slow;
+
+        impl Lookup for Slow {
+            fn lookup(&self, key: &str) -> Option<&str> {
+                if key == "slow" {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                Some("x")
+            }
+        }
+
+        let template = Template::new("$fast$ $slow$").unwrap();
+        let (rendered, timings) = template.render_timed(&Slow);
+        assert_eq!(rendered, "x x");
+        assert_eq!(timings.len(), 2);
+        assert_eq!(timings[1].0, "slow");
+        assert!(timings[1].1 >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn wrap_lookup_quotes_values() {
+        let inner = owned_map(&[("name", "Ada"), ("city", "London")]);
+        let lookup = WrapLookup::new(&inner, "\"", "\"");
+        let template = Template::new("name=$name$ city=$city$").unwrap();
+        assert_eq!(template.render_owned(&lookup), "name=\"Ada\" city=\"London\"");
+    }
+
+    #[test]
+    fn wrap_lookup_keeps_missing_keys_missing() {
+        let inner = SingleLookup::new("name", "Ada");
+        assert_eq!(WrapLookup::new(&inner, "<b>", "</b>").lookup_owned("city"), None);
+    }
+
+    #[test]
+    fn render_complete_with_all_variables() {
+        let template = Template::new("$a$ and $b$").unwrap();
+        assert_eq!(
+            template.render_complete(&owned_map(&[("a", "1"), ("b", "2")])),
+            Some("1 and 2".to_string())
+        );
+    }
+
+    #[test]
+    fn render_complete_with_missing_variable() {
+        let template = Template::new("$a$ and $b$").unwrap();
+        assert_eq!(template.render_complete(&SingleLookup::new("a", "1")), None);
+    }
+
+    #[cfg(feature = "json")]
+    #[derive(serde::Serialize)]
+    struct Order {
+        id: u32,
+        customer: Customer,
+        items: Vec<&'static str>,
+    }
+
+    #[cfg(feature = "json")]
+    #[derive(serde::Serialize)]
+    struct Customer {
+        name: String,
+        vip: bool,
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn render_serialize_resolves_dotted_paths() {
+        let order = Order {
+            id: 7,
+            customer: Customer {
+                name: "Ada".to_string(),
+                vip: true,
+            },
+            items: vec!["tea", "cake"],
+        };
+        let template =
+            Template::new("#$id$ for $customer.name$ (vip: $customer.vip$): $items.1$$items.2$")
+                .unwrap();
+        assert_eq!(
+            template.render_serialize(&order).unwrap(),
+            "#7 for Ada (vip: true): cake"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_lookup_skips_non_scalar_values() {
+        let lookup = JsonLookup::new(serde_json::json!({"a": {"b": [1, 2]}, "n": null}));
+        assert_eq!(lookup.lookup_owned("a.b.0"), Some("1".to_string()));
+        assert_eq!(lookup.lookup_owned("a.b"), None);
+        assert_eq!(lookup.lookup_owned("n"), None);
+        assert_eq!(lookup.lookup_owned("a.x"), None);
+    }
+
+    #[test]
+    fn render_with_defaults_coalesces_variables() {
+        let template = Template::new("[$a??b$]").unwrap();
+        assert_eq!(
+            template.render_with_defaults(&owned_map(&[("a", "1"), ("b", "2")])),
+            "[1]"
+        );
+        assert_eq!(template.render_with_defaults(&SingleLookup::new("b", "2")), "[2]");
+        assert_eq!(template.render_with_defaults(&EmptyLookup {}), "[]");
+    }
+
+    #[test]
+    fn render_with_defaults_coalesces_before_default() {
+        let template = Template::new("$a??b??c:none$").unwrap();
+        assert_eq!(template.render_with_defaults(&SingleLookup::new("c", "3")), "3");
+        assert_eq!(template.render_with_defaults(&EmptyLookup {}), "none");
+    }
+
+    #[test]
+    fn render_stats_splits_bytes() {
+        let template = Template::new("Hello $who$, $$5 $missing$!").unwrap();
+        assert_eq!(
+            template.render_stats(&SingleLookup::new("who", "wörld")),
+            RenderStats {
+                output: "Hello wörld, $5 !".to_string(),
+                constant_bytes: 12,
+                variable_bytes: 6,
+            }
+        );
+    }
+
+    fn catalog() -> HashMap<String, String> {
+        owned_map(&[("greeting", "Hallo"), ("files", "eine Datei|mehrere Dateien")])
+    }
+
+    #[test]
+    fn catalog_lookup_translates_ids() {
+        let template = Template::new("$greeting$!").unwrap();
+        assert_eq!(template.render_owned(&CatalogLookup::new(catalog())), "Hallo!");
+    }
+
+    #[test]
+    fn catalog_lookup_falls_back_to_id() {
+        let lookup = CatalogLookup::new(catalog());
+        assert_eq!(lookup.lookup_owned("farewell"), Some("farewell".to_string()));
+        assert_eq!(lookup.lookup_owned("farewell:2"), Some("farewell".to_string()));
+    }
+
+    #[test]
+    fn catalog_lookup_selects_plural_forms() {
+        let args = owned_map(&[("one", "1"), ("many", "3")]);
+        let lookup = CatalogLookup::with_args(catalog(), &args);
+        let template = Template::new("$files:one$, $files:many$, $files:0$, $files$").unwrap();
+        assert_eq!(
+            template.render_owned(&lookup),
+            "eine Datei, mehrere Dateien, mehrere Dateien, eine Datei"
+        );
+    }
+
+    #[test]
+    fn render_html_escapes_values() {
+        let template = Template::new("<p>$comment$</p>").unwrap();
+        assert_eq!(
+            template.render_html(&SingleLookup::new("comment", "<b>hi</b> & bye")),
+            "<p>&lt;b&gt;hi&lt;/b&gt; &amp; bye</p>"
+        );
+    }
+
+    #[test]
+    fn render_html_passes_raw_values_through() {
+        let template = Template::new("<p>$body!raw$ $body$</p>").unwrap();
+        assert_eq!(
+            template.render_html(&SingleLookup::new("body", "<b>hi</b>")),
+            "<p><b>hi</b> &lt;b&gt;hi&lt;/b&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn lookup_with_source_names_winning_layer() {
+        let first = SingleLookup::new("a", "1");
+        let second = owned_map(&[("a", "shadowed"), ("b", "2")]);
+        let mut chain = ChainedLookup::new();
+        chain.add(&first);
+        chain.add(&second);
+        assert_eq!(chain.lookup_with_source("b"), Some((1, "2")));
+        assert_eq!(chain.lookup_with_source("a"), Some((0, "1")));
+        assert_eq!(chain.lookup_with_source("c"), None);
+    }
+
+    #[test]
+    fn render_expand_pulls_in_sub_templates() {
+        let template = Template::new("$header$|$body$|$footer$").unwrap();
+        let lookup = SingleLookup::new("name", "Ada");
+        let rendered = template.render_expand(&lookup, |name| match name {
+            "header" => Some(Template::new("Hi $name$ $greeting$").unwrap()),
+            "greeting" => Some(Template::new("welcome").unwrap()),
+            _ => None,
+        });
+        assert_eq!(rendered, "Hi Ada welcome||");
+    }
+
+    #[test]
+    fn set_owned_matches_set() {
+        let template = Template::new("$a$ $b$ $a$").unwrap();
+        assert_eq!(
+            template.clone().set_owned("a", "1".to_string()),
+            template.clone().set("a", "1")
+        );
+        assert_eq!(template.clone().set_owned("c", "1".to_string()), template);
+    }
+
+    #[test]
+    fn set_owned_moves_the_value() {
+        let value = String::from("a value that is moved rather than copied");
+        let address = value.as_ptr();
+        let template = Template::new("<$a$>").unwrap().set_owned("a", value);
+        match &template.fragments[1] {
+            Fragment::Constant(text) => assert_eq!(text.as_ptr(), address),
+            fragment => panic!("unexpected fragment: {:?}", fragment),
+        }
+    }
+
+    #[cfg(feature = "percent-encoding")]
+    #[test]
+    fn query_lookup_parses_parameters() {
+        let lookup = QueryLookup::parse("?name=Ada&lang=en&flag");
+        let template = Template::new("$name$ ($lang$) [$flag$]").unwrap();
+        assert_eq!(template.render(&lookup), "Ada (en) []");
+    }
+
+    #[cfg(feature = "percent-encoding")]
+    #[test]
+    fn query_lookup_decodes_values() {
+        let lookup = QueryLookup::parse("q=two%20words+more&sym=%24%26");
+        assert_eq!(lookup.lookup("q"), Some("two words more"));
+        assert_eq!(lookup.lookup("sym"), Some("$&"));
+    }
+
+    #[cfg(feature = "percent-encoding")]
+    #[test]
+    fn query_lookup_keeps_first_repeated_value() {
+        let lookup = QueryLookup::parse("tag=first&tag=second");
+        assert_eq!(lookup.lookup("tag"), Some("first"));
+        assert_eq!(lookup.lookup("missing"), None);
+    }
+
+    #[test]
+    fn decompose_round_trips_render_order() {
+        let template = Template::new("Hi $name$, $$5 for $item$ $name$").unwrap();
+        let (constants, variables, order) = template.decompose();
+        assert_eq!(constants, ["Hi ", ", $5 for ", " "]);
+        assert_eq!(variables, ["name", "item", "name"]);
+
+        let lookup = owned_map(&[("name", "Ada"), ("item", "tea")]);
+        let rendered: String = order
+            .iter()
+            .map(|&(is_variable, index)| {
+                if is_variable {
+                    lookup[&variables[index]].as_str()
+                } else {
+                    constants[index].as_str()
+                }
+            })
+            .collect();
+        assert_eq!(rendered, template.render(&lookup));
+    }
+
+    #[test]
+    fn escaped_dollar_in_name() {
+        let template = Template::new(r"price: $foo\$bar$!").unwrap();
+        assert_eq!(variable_names(&template), [Arc::from("foo$bar")]);
+        assert_eq!(
+            template.render(&SingleLookup::new("foo$bar", "5")),
+            "price: 5!"
+        );
+        assert!(Template::is_valid(r"$foo\$bar$"));
+        assert!(!Template::is_valid(r"$foo\$"));
+    }
+
+    #[test]
+    fn escaped_dollar_in_name_round_trips() {
+        let template = Template::new(r"$foo\$bar$ $\$$ a\$$ $b\c$ $d\\$").unwrap();
+        assert_eq!(template.as_spec(), r"$foo\$bar$ $\$$ a\$$ $b\\c$ $d\\$");
+        assert_eq!(
+            variable_names(&template),
+            [
+                Arc::from("foo$bar"),
+                Arc::from("$"),
+                Arc::from(r"b\c"),
+                Arc::from(r"d\")
+            ]
+        );
+        assert_eq!(Template::new(&template.as_spec()), Ok(template));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn render_parallel_resolves_each_variable_once() {
+        use std::sync::Mutex;
+
+        struct Counting {
+            values: HashMap<String, String>,
+            calls: Mutex<Vec<String>>,
+        }
+
+        impl Lookup for Counting {
+            fn lookup(&self, key: &str) -> Option<&str> {
+                self.calls.lock().unwrap().push(key.to_string());
+                self.values.get(key).map(|value| value.as_str())
+            }
+        }
+
+        let lookup = Counting {
+            values: owned_map(&[("a", "1"), ("b", "2"), ("c", "3")]),
+            calls: Mutex::new(Vec::new()),
+        };
+        let template = Template::new("$a$ $b$ $c$ $a$ $missing$ $b$").unwrap();
+        assert_eq!(template.render_parallel(&lookup), "1 2 3 1  2");
+
+        let mut calls = lookup.calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls, ["a", "b", "c", "missing"]);
+    }
+
+    #[test]
+    fn namespaced_lookup_resolves_across_namespaces() {
+        let user = owned_map(&[("name", "Ada")]);
+        let env = owned_map(&[("HOME", "/home/ada")]);
+        let app = owned_map(&[("version", "1.2"), ("build.id", "42")]);
+        let mut lookup = NamespacedLookup::new();
+        lookup.add("user", &user).add("env", &env).add("app", &app);
+        let template =
+            Template::new("$user.name$ $env.HOME$ $app.version$ $app.build.id$").unwrap();
+        assert_eq!(template.render(&lookup), "Ada /home/ada 1.2 42");
+    }
+
+    #[test]
+    fn namespaced_lookup_needs_known_namespace() {
+        let user = owned_map(&[("name", "Ada")]);
+        let mut lookup = NamespacedLookup::new();
+        lookup.add("user", &user);
+        assert_eq!(lookup.lookup("name"), None);
+        assert_eq!(lookup.lookup("group.name"), None);
+    }
+
+    #[test]
+    fn partial_shared_shares_repeated_values() {
+        let template = Template::new("$big$ and $big$, $other$ $missing$").unwrap();
+        let big = "x".repeat(1000);
+        let lookup = owned_map(&[("big", &big), ("other", "y")]);
+        let shared = template.partial_shared(&lookup);
+        assert_eq!(shared, template.partial(&lookup));
+        match (&shared.fragments[0], &shared.fragments[2]) {
+            (Fragment::Constant(Text::Shared(a)), Fragment::Constant(Text::Shared(b))) => {
+                assert!(Arc::ptr_eq(a, b))
+            }
+            fragments => panic!("unexpected fragments: {:?}", fragments),
+        }
+    }
+
+    #[test]
+    fn shared_values_are_copied_on_modification() {
+        let template = Template::new("$a$$a$ ").unwrap();
+        let mut shared = template.partial_shared(&SingleLookup::new("a", " v "));
+        shared.push_constant("!");
+        assert_eq!(shared.as_spec(), " v  v  !");
+        assert_eq!(shared.normalize().as_spec(), " v  v  !");
+    }
+
+    #[test]
+    fn line_comments_are_dropped() {
+        let options = ParseOptions::new().line_comments(true);
+        let spec = "host = $host$ $//$ the host name\nport = $port$$//$ $unused$\n$//$ end";
+        let template = Template::with_options(spec, &options).unwrap();
+        assert_eq!(template.as_spec(), "host = $host$ \nport = $port$\n");
+        assert_eq!(template, Template::new("host = $host$ \nport = $port$\n").unwrap());
+    }
+
+    #[test]
+    fn line_comment_markers_within_names_are_names() {
+        let options = ParseOptions::new().line_comments(true);
+        let template = Template::with_options("$a//b$ $//x$ rest", &options).unwrap();
+        assert_eq!(template.as_spec(), "$a//b$ $//x$ rest");
+        let template = Template::with_options("$//$ comment", &ParseOptions::new()).unwrap();
+        assert_eq!(variable_names(&template), [Arc::from("//")]);
+    }
+
+    #[test]
+    fn render_mut_supports_stateful_lookups() {
+        struct Counter {
+            next: usize,
+        }
+
+        impl MutLookup for Counter {
+            fn lookup_mut(&mut self, key: &str) -> Option<String> {
+                if key != "n" {
+                    return None;
+                }
+                self.next += 1;
+                Some(self.next.to_string())
+            }
+        }
+
+        let template = Template::new("$n$. a\n$n$. b\n$other$$n$. c").unwrap();
+        let mut counter = Counter { next: 0 };
+        assert_eq!(template.render_mut(&mut counter), "1. a\n2. b\n3. c");
+        assert_eq!(counter.next, 3);
+    }
+
+    #[test]
+    fn render_delta_reports_changed_variables() {
+        let template = Template::new("<h1>$title$</h1><p>$count$</p>").unwrap();
+        let old = owned_map(&[("title", "Inbox"), ("count", "3")]);
+        let new = owned_map(&[("title", "Inbox"), ("count", "4")]);
+        assert_eq!(template.render_delta(&old, &new), [(3, "4".to_string())]);
+        assert_eq!(template.render_delta(&old, &old), []);
+        assert_eq!(
+            template.render_delta(&old, &SingleLookup::new("title", "Inbox")),
+            [(3, String::new())]
+        );
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn captures_lookup_uses_defaults_for_unmatched_groups() {
+        let template = Template::new("$domain:none$$tld:.org$ [$9:no group$]").unwrap();
+        assert_eq!(
+            template.render_with_defaults(&captures_lookup("ada@example.com")),
+            "example.com [no group]"
+        );
+        assert_eq!(
+            template.render_with_defaults(&captures_lookup("ada@example")),
+            "example.org [no group]"
+        );
+    }
+
+    #[test]
+    fn render_over_layers_overrides_on_base() {
+        let template = Template::new("$greeting$, $name$!").unwrap();
+        let base = owned_map(&[("greeting", "Hello"), ("name", "world")]);
+        assert_eq!(
+            template.render_over(&base, &[("name", "Ada"), ("unused", "x")]),
+            "Hello, Ada!"
+        );
+        assert_eq!(template.render_over(&base, &[]), "Hello, world!");
+    }
+
+    #[test]
+    fn whitespace_control_handles_crlf() {
+        let options = ParseOptions::new().whitespace_control(true);
+        let unix = Template::with_options("a\n  $-x-$\n  b", &options).unwrap();
+        let windows = Template::with_options("a\r\n  $-x-$\r\n  b", &options).unwrap();
+        assert_eq!(windows.as_spec(), "a$x$b");
+        assert_eq!(windows, unix);
+
+        let windows = Template::with_options("a \r\n$x-$ b", &options).unwrap();
+        assert_eq!(windows.as_spec(), "a \r\n$x$b");
+    }
+
+    #[test]
+    fn line_comments_keep_crlf() {
+        let options = ParseOptions::new().line_comments(true);
+        let unix = Template::with_options("a = 1 $//$ note\nb = 2\n", &options).unwrap();
+        let windows = Template::with_options("a = 1 $//$ note\r\nb = 2\r\n", &options).unwrap();
+        assert_eq!(unix.as_spec(), "a = 1 \nb = 2\n");
+        assert_eq!(windows.as_spec(), "a = 1 \r\nb = 2\r\n");
+    }
+
+    #[test]
+    fn terminators_keep_crlf() {
+        let options = ParseOptions::new().terminators(&['\n']);
+        let template = Template::with_options("$name\r\nnext $\r\n", &options).unwrap();
+        assert_eq!(variable_names(&template), [Arc::from("name")]);
+        assert_eq!(
+            template.render(&SingleLookup::new("name", "Ada")),
+            "Ada\r\nnext $\r\n"
+        );
+    }
+
+    #[test]
+    fn flat_lookup_resolves_dotted_keys() {
+        let lookup = FlatLookup::new(owned_map(&[("user.name", "Ada")]));
+        let template = Template::new("$user.name$$user$").unwrap();
+        assert_eq!(template.render(&lookup), "Ada");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn flat_lookup_flattens_nested_json() {
+        let value = serde_json::json!({
+            "user": {"name": "Ada", "langs": ["en", "fr"], "admin": false, "team": null},
+            "id": 7
+        });
+        let lookup = FlatLookup::flatten(&value);
+        assert_eq!(
+            lookup.values,
+            owned_map(&[
+                ("user.name", "Ada"),
+                ("user.langs.0", "en"),
+                ("user.langs.1", "fr"),
+                ("user.admin", "false"),
+                ("id", "7"),
+            ])
+        );
+        let template = Template::new("$user.name$ ($id$)").unwrap();
+        assert_eq!(template.render(&lookup), "Ada (7)");
+    }
+
+    #[test]
+    fn render_transformed_skips_raw_variables() {
+        let template = Template::new("[$a$] $b!raw$ $missing$!").unwrap();
+        let lookup = owned_map(&[("a", "x"), ("b", "y")]);
+        assert_eq!(
+            template.render_transformed(&lookup, |text| format!("<{}>", text)),
+            "[<x>] y !"
+        );
+    }
+
+    #[test]
+    fn render_tokens_mark_dynamic_parts() {
+        let template = Template::new("Hi $name$, $missing$!").unwrap();
+        assert_eq!(
+            template.render_tokens(&SingleLookup::new("name", "Ada")),
+            [
+                Token::Literal("Hi ".to_string()),
+                Token::Value {
+                    name: "name".to_string(),
+                    value: Some("Ada".to_string()),
+                },
+                Token::Literal(", ".to_string()),
+                Token::Value {
+                    name: "missing".to_string(),
+                    value: None,
+                },
+                Token::Literal("!".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixed_env_lookup_strips_prefix() {
+        env::set_var("STT_TEST_PREFIXED_DATABASE_URL", "postgres://localhost");
+        let lookup = PrefixedEnvLookup::new("STT_TEST_PREFIXED_");
+        let template = Template::new("url=$DATABASE_URL$").unwrap();
+        assert_eq!(template.render(&lookup), "url=postgres://localhost");
+    }
+
+    #[test]
+    fn prefixed_env_lookup_hides_other_variables() {
+        env::set_var("STT_TEST_UNPREFIXED", "hidden");
+        let lookup = PrefixedEnvLookup::new("STT_TEST_PREFIXED_");
+        assert_eq!(lookup.lookup("STT_TEST_UNPREFIXED"), None);
+        assert_eq!(lookup.lookup("STT_TEST_PREFIXED_DATABASE_URL"), None);
+    }
+
+    #[cfg(feature = "num-format")]
+    #[test]
+    fn render_numbers_groups_thousands() {
+        let template = Template::new("$amount:#,###$ / $price:# ###.00$ / $n:#,###$").unwrap();
+        let lookup = owned_map(&[("amount", "1234567"), ("price", "-9876.5"), ("n", "12")]);
+        assert_eq!(template.render_numbers(&lookup), "1,234,567 / -9 876.50 / 12");
+    }
+
+    #[cfg(feature = "num-format")]
+    #[test]
+    fn render_numbers_keeps_non_numeric_values() {
+        let template = Template::new("$amount:#,###$ $amount$").unwrap();
+        let lookup = owned_map(&[("amount", "lots")]);
+        assert_eq!(template.render_numbers(&lookup), "lots lots");
+    }
+
+    #[test]
+    fn check_balance_accepts_closed_variables() {
+        assert_eq!(Template::check_balance("Hello $name$, that is $$5 $a\\$b$"), Ok(()));
+        assert_eq!(Template::check_balance(""), Ok(()));
+    }
+
+    #[test]
+    fn check_balance_reports_open_variable() {
+        assert_eq!(Template::check_balance("$a$ and $name"), Err((8, '$')));
+        assert_eq!(Template::check_balance("$a$ and $na\\$me"), Err((8, '$')));
+    }
+
+    #[test]
+    fn fallback_template_lookup_renders_missing_keys() {
+        let values = owned_map(&[("user", "ann"), ("app", "stt")]);
+        let fallback = Template::new("<$key$ unset in $app$>").unwrap();
+        let lookup = FallbackTemplateLookup::new(&values, &fallback);
+        let template = Template::new("$user$ uses $theme$").unwrap();
+        assert_eq!(template.render_owned(&lookup), "ann uses <theme unset in stt>");
+    }
+
+    #[test]
+    fn to_rust_source_escapes_spec() {
+        let template = Template::new(r#"He said "$$5" to $name$ and $a\$b$"#).unwrap();
+        assert_eq!(
+            template.to_rust_source(),
+            r#"stt::Template::new("He said \"$$5\" to $name$ and $a\\$b$").unwrap()"#
+        );
+        let generated = Template::new("He said \"$$5\" to $name$ and $a\\$b$").unwrap();
+        assert_eq!(generated, template);
+    }
+
+    #[test]
+    fn render_with_sink_stops_on_break() {
+        let template = Template::new("$a$, $b$, $c$").unwrap();
+        let lookup = owned_map(&[("a", "one"), ("b", "two"), ("c", "three")]);
+        let mut output = String::new();
+        let mut chunks = 0;
+        let flow = template.render_with_sink(&lookup, |chunk| {
+            chunks += 1;
+            output.push_str(chunk);
+            if output.len() >= 6 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert_eq!(output, "one, two");
+        assert_eq!(chunks, 3);
+    }
+
+    #[test]
+    fn render_with_sink_continues_to_end() {
+        let template = Template::new("$a$-$missing$-$a$").unwrap();
+        let lookup = owned_map(&[("a", "x")]);
+        let mut output = String::new();
+        let flow = template.render_with_sink(&lookup, |chunk| {
+            output.push_str(chunk);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(output, "x--x");
+    }
+
+    #[test]
+    fn consensus_lookup_requires_agreement() {
+        let first = owned_map(&[("host", "db1"), ("port", "5432"), ("user", "ann")]);
+        let second = owned_map(&[("host", "db1"), ("port", "5433")]);
+        let lookup = ConsensusLookup::new(&first, &second);
+        assert_eq!(lookup.lookup("host"), Some("db1"));
+        assert_eq!(lookup.lookup("port"), None);
+        assert_eq!(lookup.lookup("user"), Some("ann"));
+        assert_eq!(lookup.lookup("missing"), None);
+    }
+
+    #[test]
+    fn strict_consensus_lookup_requires_both_sources() {
+        let first = owned_map(&[("host", "db1"), ("user", "ann")]);
+        let second = owned_map(&[("host", "db1"), ("port", "5433")]);
+        let lookup = ConsensusLookup::new(&first, &second).strict(true);
+        assert_eq!(lookup.lookup("host"), Some("db1"));
+        assert_eq!(lookup.lookup("user"), None);
+        assert_eq!(lookup.lookup("port"), None);
+    }
+
+    #[test]
+    fn deferred_variables_survive_first_stage() {
+        let template = Template::new("$greeting$, $name$! Costs $$5.").unwrap();
+        let stage_one = owned_map(&[("greeting", "Hello")]);
+        let text = template.render_deferred(&stage_one);
+        assert_eq!(text, "Hello, $$name$$! Costs $5.");
+        let stage_two = owned_map(&[("name", "Ann")]);
+        let second = Template::from_deferred(&text);
+        assert_eq!(variable_names(&second), vec!["name".into()] as Vec<Arc<str>>);
+        assert_eq!(second.render(&stage_two), "Hello, Ann! Costs $5.");
+    }
+
+    #[test]
+    fn from_deferred_keeps_other_dollars_literal() {
+        let template = Template::from_deferred("$$ and $$ $$$$ $$a$$$, $");
+        assert_eq!(template.render(&owned_map(&[("a", "x")])), "$$ and $$ $$$$ x$, $");
+    }
+
+    #[test]
+    fn render_recursive_expands_values() {
+        let lookup = owned_map(&[("a", "[$b$]"), ("b", "$c$ $c$"), ("c", "c"), ("d", "$oops")]);
+        let template = Template::new("$a$ $d$ $missing$").unwrap();
+        assert_eq!(template.render_recursive(&lookup), Ok(String::from("[c c] $oops ")));
+    }
+
+    #[test]
+    fn render_recursive_detects_self_reference() {
+        let lookup = owned_map(&[("a", "x $a$")]);
+        let template = Template::new("$a$").unwrap();
+        let error = template.render_recursive(&lookup).unwrap_err();
+        assert_eq!(error, RenderError::Cycle(vec!["a".to_string(), "a".to_string()]));
+        assert_eq!(error.to_string(), "variable cycle a -> a");
+    }
+
+    #[test]
+    fn render_recursive_reports_cycle_path() {
+        let lookup = owned_map(&[("start", "$a$"), ("a", "$b$"), ("b", "$a$")]);
+        let template = Template::new("$start$").unwrap();
+        let path = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+        assert_eq!(template.render_recursive(&lookup), Err(RenderError::Cycle(path)));
+    }
+
+    #[test]
+    fn render_recursive_follows_deep_chains() {
+        let names: Vec<String> = (0..40).map(|i| format!("v{}", i)).collect();
+        let mut values: HashMap<String, String> = names
+            .windows(2)
+            .map(|pair| (pair[0].clone(), format!("${}$", pair[1])))
+            .collect();
+        values.insert("v39".to_string(), "end".to_string());
+        let template = Template::new("$v0$").unwrap();
+        assert_eq!(template.render_recursive(&values), Ok(String::from("end")));
+    }
+
+    #[test]
+    fn render_or_falls_back_to_default() {
+        let template = Template::new("$host$:$port$$path$").unwrap();
+        let primary = owned_map(&[("host", "example.com")]);
+        let default = owned_map(&[("host", "localhost"), ("port", "80")]);
+        assert_eq!(template.render_or(&primary, &default), "example.com:80");
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn render_encoded_base64() {
+        let template = Template::new("$data:base64$ $data$").unwrap();
+        let lookup = owned_map(&[("data", "hello, world")]);
+        assert_eq!(template.render_encoded(&lookup), "aGVsbG8sIHdvcmxk hello, world");
+    }
 
+    #[cfg(feature = "hex")]
+    #[test]
+    fn render_encoded_hex() {
+        let template = Template::new("$data:hex$ $data:unknown$").unwrap();
+        let lookup = owned_map(&[("data", "stt!")]);
+        assert_eq!(template.render_encoded(&lookup), "73747421 stt!");
+    }
+
+    #[test]
+    fn cached_template_reuses_output_within_generation() {
+        let mut cached = CachedTemplate::new(Template::new("Hello $who$").unwrap());
+        let first = owned_map(&[("who", "ann")]);
+        let second = owned_map(&[("who", "bob")]);
+        assert_eq!(cached.render_cached(&first, 1), "Hello ann");
+        assert_eq!(cached.render_cached(&second, 1), "Hello ann");
+        assert_eq!(cached.render_cached(&second, 2), "Hello bob");
+        assert_eq!(cached.template().spec(), "Hello $who$");
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn csv_row_lookup_resolves_columns() {
+        let lookup = CsvRowLookup::new(strings(&["name", "email"]), strings(&["Ann", "ann@x"]));
+        assert_eq!(lookup.lookup("email"), Some("ann@x"));
+        assert_eq!(lookup.lookup("phone"), None);
+    }
+
+    #[test]
+    fn csv_row_lookup_tolerates_length_mismatch() {
+        let short = CsvRowLookup::new(strings(&["name", "email"]), strings(&["Ann"]));
+        assert_eq!(short.lookup("name"), Some("Ann"));
+        assert_eq!(short.lookup("email"), None);
+        let long = CsvRowLookup::new(strings(&["name"]), strings(&["Ann", "extra"]));
+        assert_eq!(long.lookup("name"), Some("Ann"));
+    }
+
+    #[test]
+    fn render_csv_renders_each_row() {
+        let template = Template::new("Dear $name$, <$email$>").unwrap();
+        let header = strings(&["name", "email"]);
+        let rows = vec![strings(&["Ann", "ann@x"]), strings(&["Bob"])];
+        let outputs = template.render_csv(&header, &rows);
+        assert_eq!(outputs, vec!["Dear Ann, <ann@x>", "Dear Bob, <>"]);
+    }
+
+    #[test]
+    fn render_csv_without_rows_is_empty() {
+        let template = Template::new("$name$").unwrap();
+        assert!(template.render_csv(&strings(&["name"]), &[]).is_empty());
+    }
+
+    #[test]
+    fn render_csv_leaves_unknown_columns_empty() {
+        let template = Template::new("$name$:$age$").unwrap();
+        let outputs = template.render_csv(&strings(&["name"]), &[strings(&["Ann", "30"])]);
+        assert_eq!(outputs, vec!["Ann:"]);
+    }
+
+    #[test]
+    fn disallow_escape_rejects_doubled_dollars() {
+        let options = ParseOptions::new().disallow_escape(true);
+        let error = Template::with_options("Costs $price$ or $$5", &options).unwrap_err();
+        assert_eq!(error, ParseError::UnexpectedEscape { offset: 17 });
+        assert_eq!(error.to_string(), "unexpected escape at byte 17");
+        assert!(Template::with_options("Costs $price$ $", &options).is_ok());
+    }
+
+    #[test]
+    fn escape_is_literal_by_default() {
+        let template = Template::new("Costs $$5").unwrap();
+        assert_eq!(template.render(&EmptyLookup::new()), "Costs $5");
+    }
+
+    #[test]
+    fn render_fmt_writes_into_string() {
         let template = Template::new("Hello $who$!").unwrap();
+        let mut out = String::from("> ");
+        assert!(template.render_fmt(&SingleLookup::new("who", "world"), &mut out).is_ok());
+        assert_eq!(out, "> Hello world!");
+    }
 
-        assert_eq!(template.render(&lookup), "Hello world!");
+    #[test]
+    fn render_fmt_forwards_write_errors() {
+        struct FailingSink {
+            written: String,
+            writes_left: usize,
+        }
+
+        impl fmt::Write for FailingSink {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                if self.writes_left == 0 {
+                    return Err(fmt::Error);
+                }
+                self.writes_left -= 1;
+                self.written.push_str(s);
+                Ok(())
+            }
+        }
+
+        let template = Template::new("$a$-$b$-$c$").unwrap();
+        let lookup = owned_map(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let mut sink = FailingSink {
+            written: String::new(),
+            writes_left: 3,
+        };
+        assert_eq!(template.render_fmt(&lookup, &mut sink), Err(fmt::Error));
+        assert_eq!(sink.written, "1-2");
+    }
+
+    #[test]
+    fn struct_lookup_resolves_registered_fields() {
+        struct Order {
+            id: u32,
+            customer: String,
+        }
+
+        let order = Order {
+            id: 42,
+            customer: String::from("Ann"),
+        };
+        let mut lookup = StructLookup::new(&order);
+        lookup
+            .field("id", |order: &Order| order.id.to_string())
+            .field("customer", |order: &Order| order.customer.clone());
+        let template = Template::new("Order $id$ for $customer$$note$").unwrap();
+        assert_eq!(template.render_owned(&lookup), "Order 42 for Ann");
+    }
+
+    #[test]
+    fn mixed_lookup_prefers_positional_arguments() {
+        let named = owned_map(&[("name", "Ann"), ("0", "zero"), ("1", "one")]);
+        let positional = ["first"];
+        let lookup = MixedLookup::new(&positional, &named);
+        let template = Template::new("$0$ $name$ $1$$2$").unwrap();
+        assert_eq!(template.render(&lookup), "first Ann one");
+    }
+
+    #[test]
+    fn render_plural_selects_form_by_count() {
+        let template = Template::new("$count$ $count:item|items$").unwrap();
+        let render = |count| template.render_plural(&SingleLookup::new("count", count));
+        assert_eq!(render("1"), "1 item");
+        assert_eq!(render("0"), "0 items");
+        assert_eq!(render("5"), "5 items");
+        assert_eq!(render("many"), "many many");
+    }
+
+    #[test]
+    fn render_json_escaped_escapes_values_only() {
+        let template = Template::new(r#"{"msg": "$msg$", "raw": "\n"}"#).unwrap();
+        let lookup = owned_map(&[("msg", "say \"hi\"\nC:\\ \u{1}")]);
+        assert_eq!(
+            template.render_json_escaped(&lookup),
+            r#"{"msg": "say \"hi\"\nC:\\ \u0001", "raw": "\n"}"#
+        );
+    }
+
+    #[test]
+    fn weak_cache_lookup_refetches_dropped_values() {
+        let fetches = std::cell::Cell::new(0);
+        let inner = FnLookup(|key: &str| {
+            fetches.set(fetches.get() + 1);
+            Some(key.to_uppercase())
+        });
+        let lookup = WeakCacheLookup::new(&inner);
+        let held = lookup.lookup_rc("big").unwrap();
+        let again = lookup.lookup_rc("big").unwrap();
+        assert!(Rc::ptr_eq(&held, &again));
+        assert_eq!(fetches.get(), 1);
+        drop(held);
+        drop(again);
+        assert_eq!(lookup.lookup_rc("big").as_deref(), Some("BIG"));
+        assert_eq!(fetches.get(), 2);
+    }
+
+    #[test]
+    fn weak_cache_lookup_prunes_dropped_entries() {
+        let inner = FnLookup(|key: &str| Some(key.to_string()));
+        let lookup = WeakCacheLookup::new(&inner);
+        let held = lookup.lookup_rc("held").unwrap();
+        for i in 0..WEAK_CACHE_MIN_PRUNE * 4 {
+            drop(lookup.lookup_rc(&i.to_string()));
+            assert!(lookup.cache.borrow().len() < WEAK_CACHE_MIN_PRUNE);
+        }
+        assert!(Rc::ptr_eq(&held, &lookup.lookup_rc("held").unwrap()));
+    }
+
+    #[test]
+    fn render_with_map_covers_each_fragment() {
+        let template = Template::new("Hi $name$,$missing$ bye").unwrap();
+        let (output, map) = template.render_with_map(&SingleLookup::new("name", "Ann"));
+        assert_eq!(output, "Hi Ann, bye");
+        assert_eq!(map, vec![(0..3, 0), (3..6, 1), (6..7, 2), (7..7, 3), (7..11, 4)]);
+        assert_eq!(&output[map[1].0.clone()], "Ann");
+    }
+
+    #[test]
+    fn or_compute_fills_in_empty_and_missing_values() {
+        let values = owned_map(&[("name", "Ann"), ("title", "")]);
+        let lookup = (&values).or_compute(|key| format!("<{}>", key));
+        let template = Template::new("$name$ $title$ $email$").unwrap();
+        assert_eq!(template.render_owned(&lookup), "Ann <title> <email>");
+    }
+
+    #[test]
+    fn render_max_missing_tolerates_few_missing() {
+        let template = Template::new("$a$ $b$ $b$ $c$").unwrap();
+        let lookup = owned_map(&[("a", "1")]);
+        assert_eq!(template.render_max_missing(&lookup, 2), Ok(String::from("1   ")));
+    }
+
+    #[test]
+    fn render_max_missing_fails_with_count() {
+        let template = Template::new("$a$ $b$ $b$ $c$").unwrap();
+        let error = template.render_max_missing(&EmptyLookup::new(), 2).unwrap_err();
+        assert_eq!(error, RenderError::TooManyMissing(3));
+        assert_eq!(error.to_string(), "3 variables missing");
+    }
+
+    #[test]
+    fn render_strategy_controls_all_output() {
+        struct Bracketing;
+
+        impl RenderStrategy for Bracketing {
+            fn write_constant(&self, out: &mut String, text: &str) {
+                out.push_str(&text.to_uppercase());
+            }
+
+            fn write_variable(&self, out: &mut String, name: &str, value: Option<&str>) {
+                match value {
+                    Some(value) => out.push_str(&format!("[{}]", value)),
+                    None => out.push_str(&format!("[?{}]", name)),
+                }
+            }
+        }
+
+        let template = Template::new("hello $who$, meet $other$").unwrap();
+        let output = template.render_strategy(&SingleLookup::new("who", "ann"), &Bracketing);
+        assert_eq!(output, "HELLO [ann], MEET [?other]");
+    }
+
+    #[test]
+    fn templates_can_move_between_threads() {
+        let template = Template::new("Hello $who$").unwrap();
+        let shared = template.partial_shared(&owned_map(&[("x", "y")]));
+        let rendered = std::thread::spawn(move || shared.render(&SingleLookup::new("who", "ann")))
+            .join()
+            .unwrap();
+        assert_eq!(rendered, "Hello ann");
+    }
+
+    #[test]
+    fn templates_can_be_shared_between_threads() {
+        let template = Arc::new(Template::new("Hello $who$").unwrap());
+        let handles: Vec<_> = ["ann", "bob"]
+            .iter()
+            .map(|who| {
+                let template = Arc::clone(&template);
+                std::thread::spawn(move || {
+                    assert_eq!(template.spec(), "Hello $who$");
+                    template.render(&SingleLookup::new("who", who))
+                })
+            })
+            .collect();
+        let rendered: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(rendered, vec!["Hello ann", "Hello bob"]);
+    }
+
+    #[test]
+    fn spec_round_trips_backslashes_in_names() {
+        let template = Template::from_tokens(vec![(true, "a\\".into()), (false, "x".into())]);
+        assert_eq!(template.as_spec(), r"$a\\$x");
+        assert_eq!(Template::new(&template.as_spec()), Ok(template));
+    }
+
+    #[test]
+    fn empty_variable_names_are_left_out() {
+        let template = Template::from_tokens(vec![
+            (false, "a".into()),
+            (true, String::new()),
+            (true, "b".into()),
+        ]);
+        assert_eq!(template.to_tokens(), vec![(false, "a".into()), (true, "b".into())]);
+        let fragments = vec![PublicFragment::Variable(String::new())];
+        assert_eq!(Template::from_fragments(fragments), Template::empty());
+        let mut pushed = Template::new("x").unwrap();
+        pushed.push_variable("").push_constant("y");
+        assert_eq!(pushed, Template::new("xy").unwrap());
     }
 
+    #[test]
+    fn for_each_variable_mut_removes_emptied_variables() {
+        let mut template = Template::new("a $drop$ b $keep$").unwrap();
+        template.for_each_variable_mut(|name| {
+            if name == "drop" {
+                name.clear();
+            }
+        });
+        assert_eq!(template, Template::new("a  b $keep$").unwrap());
+        assert_eq!(template.spec(), "a  b $keep$");
+    }
+
+    #[test]
+    fn braces_reject_empty_name() {
+        let options = ParseOptions::new().braces(true);
+        assert_eq!(
+            Template::with_options("a${}b", &options),
+            Err(ParseError::InvalidVariableName {
+                name: String::new()
+            })
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn render_parallel_handles_more_variables_than_threads() {
+        let values: HashMap<String, String> =
+            (0..40).map(|i| (format!("v{}", i), i.to_string())).collect();
+        let spec: String = (0..40).map(|i| format!("$v{}$,", i)).collect();
+        let template = Template::new(&spec).unwrap();
+        assert_eq!(template.render_parallel(&values), template.render(&values));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    #[should_panic(expected = "lookup failed")]
+    fn render_parallel_propagates_lookup_panics() {
+        struct Failing;
+
+        impl Lookup for Failing {
+            fn lookup(&self, _key: &str) -> Option<&str> {
+                panic!("lookup failed")
+            }
+        }
+
+        Template::new("$a$").unwrap().render_parallel(&Failing);
+    }
+
+    #[test]
+    fn render_aligned_supports_wide_columns() {
+        let template = Template::new("[$x:70000$][$x:-70000$]").unwrap();
+        let rendered = template.render_aligned(&SingleLookup::new("x", "é"));
+        let padding = " ".repeat(69999);
+        assert_eq!(rendered, format!("[{}é][é{}]", padding, padding));
+    }
+
+    #[test]
+    fn defaults_are_literal_without_nested_defaults() {
+        let template = Template::new(r"$price:US\$5\$ each$ $p:\$\$$").unwrap();
+        assert_eq!(template.render_with_defaults(&EmptyLookup::new()), "US$5$ each $$");
+    }
+
+    #[test]
+    fn nested_defaults_survive_partial() {
+        let options = ParseOptions::new().nested_defaults(true);
+        let template = Template::with_options("$who$: $name:$fallback$$", &options).unwrap();
+        let partial = template.partial(&SingleLookup::new("who", "Ann"));
+        let rendered = partial.render_with_defaults(&SingleLookup::new("fallback", "guest"));
+        assert_eq!(rendered, "Ann: guest");
+    }
+
+    #[test]
+    fn nested_defaults_report_invalid_default() {
+        let options = ParseOptions::new().nested_defaults(true).unique_variables(true);
+        assert_eq!(
+            Template::with_options("$a:$b$ $b$$", &options),
+            Err(ParseError::DuplicateVariable {
+                name: String::from("b")
+            })
+        );
+    }
 }
@@ -0,0 +1,93 @@
+//! Provides `#[derive(Lookup)]` and `#[derive(OwnedLookup)]` for the stt crate, re-exported by it
+//! as `stt::Lookup` and `stt::OwnedLookup` when its `derive` feature is enabled.
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields};
+
+/// Implements `stt::Lookup` for a struct with named fields, all of which are `AsRef<str>`,
+/// resolving each field name to the field's text.
+#[proc_macro_derive(Lookup)]
+pub fn derive_lookup(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    let names = match field_names(&input, "Lookup") {
+        Ok(names) => names,
+        Err(error) => return error,
+    };
+    let keys = names.iter().map(|name| name.to_string());
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let expanded = quote! {
+        impl #impl_generics ::stt::Lookup for #name #type_generics #where_clause {
+            fn lookup(&self, key: &str) -> ::std::option::Option<&str> {
+                match key {
+                    #(#keys => ::std::option::Option::Some(
+                        ::std::convert::AsRef::<str>::as_ref(&self.#names)
+                    ),)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+        }
+    };
+    expanded.into()
+}
+
+/// Implements `stt::OwnedLookup` for a struct with named fields, resolving each field name to
+/// the `Display` output of the field.
+#[proc_macro_derive(OwnedLookup)]
+pub fn derive_owned_lookup(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    let names = match field_names(&input, "OwnedLookup") {
+        Ok(names) => names,
+        Err(error) => return error,
+    };
+    let keys = names.iter().map(|name| name.to_string());
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let expanded = quote! {
+        impl #impl_generics ::stt::OwnedLookup for #name #type_generics #where_clause {
+            fn lookup_owned(&self, key: &str) -> ::std::option::Option<::std::string::String> {
+                match key {
+                    #(#keys => ::std::option::Option::Some(
+                        ::std::string::ToString::to_string(&self.#names)
+                    ),)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+        }
+    };
+    expanded.into()
+}
+
+fn field_names<'a>(
+    input: &'a DeriveInput,
+    derive: &str,
+) -> Result<Vec<&'a syn::Ident>, TokenStream> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields
+                .named
+                .iter()
+                .filter_map(|field| field.ident.as_ref())
+                .collect()),
+            _ => Err(error(
+                &input.ident,
+                &format!("{} can only be derived for named fields", derive),
+            )),
+        },
+        _ => Err(error(
+            &input.ident,
+            &format!("{} can only be derived for structs", derive),
+        )),
+    }
+}
+
+fn error(name: &syn::Ident, message: &str) -> TokenStream {
+    syn::Error::new(name.span(), message)
+        .to_compile_error()
+        .into()
+}
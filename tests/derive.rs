@@ -0,0 +1,40 @@
+#![cfg(feature = "derive")]
+
+#[macro_use]
+extern crate stt;
+
+use stt::Template;
+
+#[derive(OwnedLookup)]
+struct User {
+    name: String,
+    email: &'static str,
+    age: u32,
+}
+
+#[derive(Lookup)]
+struct Contact {
+    name: String,
+    email: &'static str,
+}
+
+#[test]
+fn derived_owned_lookup_resolves_fields() {
+    let user = User {
+        name: "Ada".to_string(),
+        email: "ada@example.com",
+        age: 36,
+    };
+    let template = Template::new("$name$ <$email$>, $age$ years$unknown$").unwrap();
+    assert_eq!(template.render_owned(&user), "Ada <ada@example.com>, 36 years");
+}
+
+#[test]
+fn derived_lookup_renders_borrowed_fields() {
+    let contact = Contact {
+        name: "Ada".to_string(),
+        email: "ada@example.com",
+    };
+    let template = Template::new("$name$ <$email$>$unknown$").unwrap();
+    assert_eq!(template.render(&contact), "Ada <ada@example.com>");
+}